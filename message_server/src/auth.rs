@@ -0,0 +1,264 @@
+// accounts, passwords and login tokens.
+// Passwords are stored as salted argon2 hashes; a successful login hands out
+// a random token, which is what the websocket and history routes check.
+// Tokens are only stored as their sha-256 hash, so reading the database doesn't log anyone in.
+
+use crate::identity::{make_user_id, UserId};
+use crate::message_server::{ServerError, ServerHandle};
+use crate::packet::get_current_time;
+use crate::protocol::Timestamp;
+use crate::storage::MessageDAOError;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::task::spawn_blocking;
+use rocket::State;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::sync::LazyLock;
+
+/// How long a login lasts, in microseconds
+const TOKEN_LIFETIME: Timestamp = 30 * 24 * 60 * 60 * 1_000_000;
+const MAX_USERNAME_LEN: usize = 32;
+/// Random bytes in a login token
+const TOKEN_BYTES: usize = 32;
+
+/// Checked instead when there's no such user, so that takes as long as a wrong password
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("not anyone's password").expect("Unable to hash the dummy password"));
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// Names are 1-32 letters, digits, `_`, `-` or `.`
+    BadUsername,
+    EmptyPassword,
+    /// Unknown user, wrong password, or a missing/expired token
+    BadCredentials,
+    Hash(argon2::password_hash::Error),
+    DAOError(MessageDAOError),
+    /// The server couldn't be reached
+    Server(ServerError),
+}
+
+/// Someone who showed a valid login token, either as `Authorization: Bearer <token>`
+/// or (for websockets, which can't set headers) as a `token` query parameter.
+pub struct AuthenticatedUser {
+    pub user: UserId,
+    pub token: String,
+}
+
+/// Make a new account. The name has to be free.
+pub async fn create_account(server: &ServerHandle, username: &str, password: &str) -> Result<UserId, AuthError> {
+    check_username(username)?;
+    if password.is_empty() {
+        return Err(AuthError::EmptyPassword);
+    }
+    // hashing is slow on purpose, so keep it away from the server and the async runtime
+    let password = password.to_string();
+    let hash = off_thread(move || hash_password(&password)).await?;
+    let user = make_user_id(username.to_string());
+    let new_user = user.clone();
+    server.accounts(move |accounts| accounts.create_account(&new_user, hash)).await??;
+    info!("Created account {:?}", &user);
+    Ok(user)
+}
+
+/// Check a password and hand out a new login token
+pub async fn login(server: &ServerHandle, username: &str, password: &str) -> Result<String, AuthError> {
+    let user = make_user_id(username.to_string());
+    let lookup = user.clone();
+    let hash = server.accounts(move |accounts| accounts.password_hash(&lookup)).await??;
+    let password = password.to_string();
+    let verified = off_thread(move || match hash {
+        Some(hash) => verify_password(&password, &hash),
+        None => {
+            verify_password(&password, &DUMMY_HASH);
+            false
+        }
+    });
+    if !verified.await {
+        return Err(AuthError::BadCredentials);
+    }
+    let token = make_token();
+    let token_hash = hash_token(&token);
+    server
+        .accounts(move |accounts| accounts.add_token(&token_hash, &user, get_current_time() + TOKEN_LIFETIME))
+        .await??;
+    Ok(token)
+}
+
+pub async fn logout(server: &ServerHandle, token: &str) -> Result<(), AuthError> {
+    let token_hash = hash_token(token);
+    Ok(server.accounts(move |accounts| accounts.remove_token(&token_hash)).await??)
+}
+
+/// Who a login token belongs to
+pub async fn authenticate(server: &ServerHandle, token: &str) -> Result<UserId, AuthError> {
+    let token_hash = hash_token(token);
+    server
+        .accounts(move |accounts| accounts.token_user(&token_hash, get_current_time()))
+        .await??
+        .ok_or(AuthError::BadCredentials)
+}
+
+/// Run slow password work on a thread meant for blocking
+async fn off_thread<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    spawn_blocking(f).await.expect("password hashing panicked")
+}
+
+fn check_username(username: &str) -> Result<(), AuthError> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "_-.".contains(c);
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN || !username.chars().all(allowed) {
+        return Err(AuthError::BadUsername);
+    }
+    Ok(())
+}
+
+/// Salted argon2 hash, as a PHC string (algorithm, parameters & salt included)
+fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or_else(|err| {
+            warn!("Stored password hash is unreadable: {:?}", err);
+            false
+        })
+}
+
+fn make_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// What a token is stored and looked up as. Tokens are random, so a plain (unsalted) hash is enough
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+impl AuthError {
+    /// What an http route should answer with
+    pub fn status(&self) -> Status {
+        match self {
+            AuthError::BadUsername | AuthError::EmptyPassword => Status::BadRequest,
+            AuthError::BadCredentials => Status::Unauthorized,
+            AuthError::DAOError(MessageDAOError::UserExists(_)) => Status::Conflict,
+            AuthError::Server(ServerError::Stopped) => Status::ServiceUnavailable,
+            AuthError::Hash(_) | AuthError::DAOError(_) | AuthError::Server(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<MessageDAOError> for AuthError {
+    fn from(value: MessageDAOError) -> Self {
+        AuthError::DAOError(value)
+    }
+}
+
+impl From<ServerError> for AuthError {
+    fn from(value: ServerError) -> Self {
+        AuthError::Server(value)
+    }
+}
+
+impl From<argon2::password_hash::Error> for AuthError {
+    fn from(value: argon2::password_hash::Error) -> Self {
+        AuthError::Hash(value)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        let query = request.query_value::<&str>("token").and_then(Result::ok);
+        let Some(token) = header.or(query) else {
+            return Outcome::Error((Status::Unauthorized, AuthError::BadCredentials));
+        };
+        let Some(server) = request
+            .guard::<&State<ServerHandle>>()
+            .await
+            .succeeded()
+        else {
+            return Outcome::Error((Status::InternalServerError, AuthError::BadCredentials));
+        };
+        match authenticate(server, token).await {
+            Ok(user) => Outcome::Success(AuthenticatedUser {
+                user,
+                token: token.to_string(),
+            }),
+            Err(e) => Outcome::Error((e.status(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{authenticate, create_account, hash_token, login, logout, AuthError};
+    use crate::identity::make_user_id;
+    use crate::message_server::{BacklogLimits, MessageServer, ShutdownDrafts, DEFAULT_DRAFT_GRACE};
+    use crate::storage::memory_storage::MemoryMessageDatabase;
+    use crate::storage::MessageDAOError;
+
+    #[rocket::async_test]
+    async fn register_and_login() {
+        let (server, shutdown) = MessageServer::start(
+            MemoryMessageDatabase::new(),
+            BacklogLimits::default(),
+            DEFAULT_DRAFT_GRACE,
+            0,
+            ShutdownDrafts::Discard,
+        );
+        let server = &server;
+        create_account(server, "alice", "hunter2").await.unwrap();
+        assert!(matches!(
+            create_account(server, "alice", "again").await,
+            Err(AuthError::DAOError(MessageDAOError::UserExists(_)))
+        ));
+        assert!(matches!(create_account(server, "bob/../alice", "x").await, Err(AuthError::BadUsername)));
+        assert!(matches!(create_account(server, "bob", "").await, Err(AuthError::EmptyPassword)));
+
+        // the password isn't stored as given
+        let alice = make_user_id("alice".to_string());
+        let hash = server.accounts(move |accounts| accounts.password_hash(&alice)).await.unwrap().unwrap();
+        assert!(hash.is_some_and(|h| !h.contains("hunter2")));
+
+        assert!(matches!(login(server, "alice", "wrong").await, Err(AuthError::BadCredentials)));
+        assert!(matches!(login(server, "nobody", "hunter2").await, Err(AuthError::BadCredentials)));
+        let token = login(server, "alice", "hunter2").await.unwrap();
+        assert_ne!(token, login(server, "alice", "hunter2").await.unwrap());
+        assert_eq!(authenticate(server, &token).await.unwrap(), make_user_id("alice".to_string()));
+
+        // neither is the token
+        let (stored, plain) = (hash_token(&token), token.clone());
+        let found = server
+            .accounts(move |accounts| (accounts.token_user(&stored, 0), accounts.token_user(&plain, 0)))
+            .await
+            .unwrap();
+        assert!(matches!(found, (Ok(Some(_)), Ok(None))));
+
+        logout(server, &token).await.unwrap();
+        assert!(matches!(authenticate(server, &token).await, Err(AuthError::BadCredentials)));
+        shutdown.stop().await;
+    }
+}
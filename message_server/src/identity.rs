@@ -1,26 +1,72 @@
+use std::fmt;
 use uuid::Uuid;
 
 pub fn make_user_id(uid: String) -> UserId {
     UserId(SimpleUserId(uid))
 }
 
+/// The same pair comes out whichever way around the two users are given
+pub fn make_user_pair(a: UserId, b: UserId) -> UserPair {
+    if a <= b { UserPair(a, b) } else { UserPair(b, a) }
+}
+
+pub fn make_session_id() -> SessionId {
+    SessionId(Uuid::new_v4())
+}
+
 pub fn make_group_chat_id() -> GroupChatId {
     GroupChatId(Uuid::new_v4())
 }
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
 struct SimpleUserId(String);
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
 pub struct UserId(SimpleUserId);
 
-pub type UserPair = (UserId, UserId);
+/// The two people in a direct conversation, in canonical order. See make_user_pair
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub struct UserPair(UserId, UserId);
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct GroupChatId(Uuid); // name: clique?
 
-impl UserId {
-    pub fn to_string(&self) -> String {
-        self.0.0.clone()
+/// One connection of a user. They may have several open at once.
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug)]
+pub struct SessionId(Uuid);
+
+impl UserPair {
+    pub fn users(&self) -> (&UserId, &UserId) {
+        (&self.0, &self.1)
+    }
+}
+
+impl GroupChatId {
+    pub fn to_uuid(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl SessionId {
+    pub fn to_uuid(self) -> Uuid {
+        self.0
+    }
+}
+
+impl From<Uuid> for SessionId {
+    fn from(value: Uuid) -> Self {
+        SessionId(value)
+    }
+}
+
+impl From<Uuid> for GroupChatId {
+    fn from(value: Uuid) -> Self {
+        GroupChatId(value)
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.0)
     }
-}
\ No newline at end of file
+}
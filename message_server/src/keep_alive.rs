@@ -0,0 +1,181 @@
+// Writing to one connection's websocket, and noticing when its client has gone quiet.
+// Connections that drop without closing only show up as silence, so quiet clients are pinged,
+// and one that hasn't said anything (a pong counts) for too long is hung up on.
+
+use crate::outgoing::{CloseReason, OutgoingReceiver};
+use crate::packet::{get_current_time, make_webpacket};
+use crate::protocol::Timestamp;
+use rocket::futures::{Sink, SinkExt, StreamExt};
+use rocket::tokio::select;
+use rocket::tokio::time::{self, Interval, MissedTickBehavior};
+use rocket_ws::frame::{CloseCode, CloseFrame};
+use rocket_ws::Message;
+use std::future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often connections are pinged, and how long one can go without a word from the client
+/// (a pong counts) before it's treated as dead. Catches connections that dropped without closing.
+/// Writing to the client may take as long as the timeout too.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// When the client was last heard from, shared between reading from and writing to its connection
+#[derive(Debug, Clone)]
+pub struct LastHeard(Arc<AtomicU64>);
+
+impl LastHeard {
+    pub fn now() -> Self {
+        LastHeard(Arc::new(AtomicU64::new(get_current_time())))
+    }
+
+    pub fn heard(&self) {
+        self.0.store(get_current_time(), Ordering::Relaxed);
+    }
+
+    /// How long (in microseconds) it's been
+    fn silent_for(&self) -> Timestamp {
+        get_current_time().saturating_sub(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Why send_packets stopped
+#[derive(Debug, PartialEq, Eq)]
+pub enum Hangup {
+    /// The server closed the connection. The client is told why, if there was a reason
+    Closed,
+    /// The client didn't say anything for longer than the timeout
+    Silent,
+    /// Writing to the client failed, or took longer than the timeout
+    Unwritable,
+}
+
+/// Writes what the server sends the connection to `sink`, pinging the client while it's quiet,
+/// until one of them hangs up.
+pub async fn send_packets<S>(mut sink: S, mut rx: OutgoingReceiver, keep_alive: Option<KeepAlive>, heard: LastHeard) -> Hangup
+where
+    S: Sink<Message> + Unpin,
+{
+    let mut pings = keep_alive.map(|keep_alive| {
+        let mut pings = time::interval_at(time::Instant::now() + keep_alive.interval, keep_alive.interval);
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        (pings, keep_alive.timeout)
+    });
+    let write_timeout = keep_alive.map(|keep_alive| keep_alive.timeout);
+    loop {
+        let msg = select! {
+            next = rx.next() => match next {
+                // convert to UPacket
+                Some((seq, server_message)) => make_webpacket(server_message, seq).try_into().unwrap(),
+                None => {
+                    if let Some(reason) = rx.close_reason() {
+                        write(&mut sink, close_message(reason), write_timeout).await;
+                    }
+                    return Hangup::Closed;
+                }
+            },
+            timeout = next_ping(&mut pings) => {
+                if heard.silent_for() > timeout.as_micros() as Timestamp {
+                    return Hangup::Silent;
+                }
+                Message::Ping(vec![])
+            }
+        };
+        if !write(&mut sink, msg, write_timeout).await {
+            return Hangup::Unwritable;
+        }
+    }
+}
+
+/// Whether `msg` was written within `limit`, if there is one
+async fn write<S>(sink: &mut S, msg: Message, limit: Option<Duration>) -> bool
+where
+    S: Sink<Message> + Unpin,
+{
+    match limit {
+        Some(limit) => matches!(time::timeout(limit, sink.send(msg)).await, Ok(Ok(()))),
+        None => sink.send(msg).await.is_ok(),
+    }
+}
+
+/// Tells the client why the server hung up on it
+fn close_message(reason: CloseReason) -> Message {
+    let (code, reason) = match reason {
+        CloseReason::Stopping => (CloseCode::Away, "Server is shutting down"),
+        CloseReason::FellBehind => (CloseCode::Again, "Fell too far behind, resume to catch up"),
+    };
+    Message::Close(Some(CloseFrame { code, reason: reason.into() }))
+}
+
+/// Waits for the next time to ping, giving the timeout to check against. Never comes if pings are off
+async fn next_ping(pings: &mut Option<(Interval, Duration)>) -> Duration {
+    match pings {
+        Some((interval, timeout)) => {
+            interval.tick().await;
+            *timeout
+        }
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::keep_alive::{send_packets, Hangup, KeepAlive, LastHeard};
+    use crate::outgoing::{channel, CloseReason};
+    use rocket::futures::channel::mpsc;
+    use rocket::tokio::time;
+    use rocket_ws::Message;
+    use std::time::Duration;
+
+    const KEEP_ALIVE: KeepAlive = KeepAlive {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_millis(50),
+    };
+
+    #[rocket::async_test]
+    async fn silent_clients_are_dropped() {
+        let (_tx, rx) = channel(4);
+        let (sink, mut written) = mpsc::unbounded();
+        let hangup = time::timeout(Duration::from_secs(5), send_packets(sink, rx, Some(KEEP_ALIVE), LastHeard::now()));
+        assert_eq!(hangup.await, Ok(Hangup::Silent));
+        // they were pinged first
+        assert!(matches!(written.try_recv(), Ok(Message::Ping(_))));
+    }
+
+    #[rocket::async_test]
+    async fn chatty_clients_stay() {
+        let (tx, rx) = channel(4);
+        let (sink, _written) = mpsc::unbounded();
+        let heard = LastHeard::now();
+        let sending = rocket::tokio::spawn(send_packets(sink, rx, Some(KEEP_ALIVE), heard.clone()));
+        // answering every ping, for several timeouts
+        for _ in 0..30 {
+            time::sleep(KEEP_ALIVE.interval).await;
+            heard.heard();
+        }
+        assert!(!sending.is_finished());
+        tx.close(CloseReason::Stopping);
+        assert_eq!(sending.await.unwrap(), Hangup::Closed);
+    }
+
+    #[rocket::async_test]
+    async fn stalled_writes_are_dropped() {
+        let (_tx, rx) = channel(4);
+        // room for one ping, which is never read
+        let (sink, _written) = mpsc::channel(0);
+        let heard = LastHeard::now();
+        let chatty = heard.clone();
+        rocket::tokio::spawn(async move {
+            loop {
+                time::sleep(KEEP_ALIVE.interval).await;
+                chatty.heard();
+            }
+        });
+        let hangup = time::timeout(Duration::from_secs(5), send_packets(sink, rx, Some(KEEP_ALIVE), heard));
+        assert_eq!(hangup.await, Ok(Hangup::Unwritable));
+    }
+}
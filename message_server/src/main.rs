@@ -1,116 +1,299 @@
 #[macro_use]
 extern crate rocket;
 
-use crate::packet::{make_server_packet, make_webpacket, SPacket};
-use identity::make_user_id;
-use log::{error, info, warn};
+use crate::packet::{make_server_packet, MessageRecord, Seq};
+use identity::{make_user_id, make_user_pair, SessionId};
+use log::{error, info};
 use packet::WebPacket;
-use rocket::futures::channel::mpsc::UnboundedReceiver;
-use rocket::futures::{SinkExt, StreamExt};
+use rocket::futures::StreamExt;
+use rocket::http::Status;
 use rocket::response::status;
+use rocket::serde::json::Json;
 use rocket::tokio::select;
 use rocket::{tokio, State};
 use rocket_ws::stream::DuplexStream;
 use rocket_ws::{Channel, Message, WebSocket};
-use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc, Mutex};
+use uuid::Uuid;
+use crate::message_server::{
+    BacklogLimits, Incoming, ServerError, ServerHandle, ShutdownDrafts, ShutdownHandler, DEFAULT_DRAFT_GRACE,
+};
+use crate::keep_alive::{send_packets, Hangup, KeepAlive, LastHeard};
+use crate::outgoing::OutgoingReceiver;
 use crate::storage::memory_storage::MemoryMessageDatabase;
+use crate::storage::sqlite_storage::SqliteMessageDatabase;
+use crate::storage::query::{Cursor, MessageQuery, Page, TimeRange};
+use crate::storage::{RoomId, Storage};
+use crate::protocol::Timestamp;
+use crate::auth::AuthenticatedUser;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
+pub mod auth;
 mod identity;
+mod keep_alive;
 pub mod message_server;
+mod outgoing;
 pub mod packet;
 mod storage;
 mod protocol;
 
-type MessageServer = State<Arc<Mutex<message_server::MessageServer<MemoryMessageDatabase>>>>;
+type MessageServer = State<ServerHandle>;
 
-#[derive(Clone)]
-struct ServerSender(mpsc::Sender<SPacket>);
+const DEFAULT_PING_INTERVAL_SECS: u64 = 20;
+const DEFAULT_PING_TIMEOUT_SECS: u64 = 60;
 
 #[get("/")]
 fn index() -> &'static str {
     "Hi!"
 }
 
-#[get("/updates/<uid>")]
-fn updates<'r>(
-    server: &'r MessageServer,
-    server_sender: &State<ServerSender>,
+/// Filters & paging for /history. `before`/`after` are message ids; `latest` pages from the newest end.
+/// `reply_to` only gives the replies to that message.
+#[derive(FromForm)]
+struct HistoryParams {
+    sender: Option<String>,
+    started_from: Option<Timestamp>,
+    started_until: Option<Timestamp>,
+    ended_from: Option<Timestamp>,
+    ended_until: Option<Timestamp>,
+    contains: Option<String>,
+    reply_to: Option<Uuid>,
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    latest: bool,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// What /register and /login expect
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+}
+
+#[post("/register", data = "<credentials>")]
+async fn register(
+    server: &MessageServer,
+    credentials: Json<Credentials>,
+) -> Result<status::Created<&'static str>, status::Custom<&'static str>> {
+    auth::create_account(server, &credentials.username, &credentials.password)
+        .await
+        .map(|_| status::Created::new("/login"))
+        .map_err(|e| status::Custom(e.status(), "Unable to create account"))
+}
+
+/// Trade a username & password for a token to connect with
+#[post("/login", data = "<credentials>")]
+async fn login(
+    server: &MessageServer,
+    credentials: Json<Credentials>,
+) -> Result<Json<LoginResponse>, status::Custom<&'static str>> {
+    auth::login(server, &credentials.username, &credentials.password)
+        .await
+        .map(|token| Json(LoginResponse { token }))
+        .map_err(|e| status::Custom(e.status(), "Unable to log in"))
+}
+
+#[post("/logout")]
+async fn logout(server: &MessageServer, user: AuthenticatedUser) -> Result<(), status::Custom<&'static str>> {
+    auth::logout(server, &user.token).await.map_err(|e| status::Custom(e.status(), "Unable to log out"))
+}
+
+#[get("/history/<other>?<params..>")]
+async fn history(
+    server: &MessageServer,
+    user: AuthenticatedUser,
+    other: &str,
+    params: HistoryParams,
+) -> Result<Json<Vec<MessageRecord>>, status::NotFound<&'static str>> {
+    let room_id = RoomId::DM(make_user_pair(user.user, make_user_id(other.to_string())));
+    let query = MessageQuery {
+        sender: params.sender.map(make_user_id),
+        started: TimeRange { from: params.started_from, until: params.started_until },
+        ended: TimeRange { from: params.ended_from, until: params.ended_until },
+        contains: params.contains,
+        reply_to: params.reply_to,
+    };
+    let cursor = match (params.before, params.after) {
+        (Some(m_id), _) => Cursor::Before(m_id),
+        (None, Some(m_id)) => Cursor::After(m_id),
+        (None, None) if params.latest => Cursor::End,
+        (None, None) => Cursor::Start,
+    };
+    let page = Page { cursor, offset: params.offset.unwrap_or(0), limit: params.limit };
+    server
+        .history(room_id, query, page)
+        .await
+        .map(Json)
+        .map_err(|_| status::NotFound("No such conversation or message"))
+}
+
+/// Connect to receive packets, with the token from /login as `?token=`.
+/// To resume a dropped connection, pass the `session` from its
+/// Welcome packet and the last `seq` the client acknowledged.
+#[get("/updates?<session>&<seq>")]
+async fn updates(
+    server: &MessageServer,
+    keep_alive: &State<Option<KeepAlive>>,
+    user: AuthenticatedUser,
     ws: WebSocket,
-    uid: &'r str,
-) -> Result<Channel<'r>, status::Forbidden<&'static str>> {
-    let server2 = server;
-    let mut server = server.lock().unwrap();
-    let rx = server
-        .register(make_user_id(uid.to_string()))
-        .map_err(|_| status::Forbidden("Already registered"))?;
-    let tx = server_sender.0.clone();
-    Ok(ws.channel(move |stream| Box::pin(handle_socket(server2, tx, rx, stream, uid.to_string()))))
+    session: Option<Uuid>,
+    seq: Option<Seq>,
+) -> Result<Channel<'static>, status::Custom<&'static str>> {
+    let server = server.inner().clone();
+    let resume = session.map(SessionId::from).zip(seq);
+    let (session, rx) = server
+        .register(user.user.clone(), resume)
+        .await
+        .map_err(|e| match e {
+            ServerError::Stopped => status::Custom(Status::ServiceUnavailable, "Server is shutting down"),
+            _ => status::Custom(Status::Forbidden, "Unable to connect"),
+        })?;
+    let uid = user.user.to_string();
+    let keep_alive = *keep_alive.inner();
+    Ok(ws.channel(move |stream| Box::pin(handle_socket(server, session, rx, stream, uid, keep_alive))))
 }
 
 async fn handle_socket(
-    server: &MessageServer,
-    tx: Sender<SPacket>,
-    mut rx: UnboundedReceiver<SPacket>,
+    server: ServerHandle,
+    session: SessionId,
+    rx: OutgoingReceiver,
     channel: DuplexStream,
     uid: String,
+    keep_alive: Option<KeepAlive>,
 ) -> rocket_ws::result::Result<()> {
-    let (mut sender, mut receiver) = channel.split();
+    let (sender, mut receiver) = channel.split();
     let user_id = make_user_id(uid.clone());
     info!("Registered {:?}", &user_id);
+    let heard = LastHeard::now();
     // Receiving task (handles incoming messages from the WebSocket)
     let r_uid = uid.clone();
-    let receive_task = tokio::spawn(async move {
+    let tx = server.clone();
+    let r_heard = heard.clone();
+    let mut receive_task = tokio::spawn(async move {
         let r_uid = make_user_id(r_uid);
         while let Some(Ok(msg)) = receiver.next().await {
+            r_heard.heard();
             // convert to SPacket
             match msg {
                 Message::Close(_c) => {
                     info!("Closing connection.");
                     break;
                 }
-                msg => match WebPacket::try_from(msg) {
-                    Ok(upacket) => tx.send(make_server_packet(upacket, r_uid.clone())).unwrap(),
-                    Err(e) => {
-                        error!("Unable to parse upacket: {:?}", e);
+                Message::Ping(_) | Message::Pong(_) => {}
+                msg => {
+                    let incoming = match WebPacket::try_from(msg) {
+                        Ok(upacket) => {
+                            let request_id = upacket.request_id();
+                            Incoming::Packet(make_server_packet(upacket, r_uid.clone()), request_id)
+                        }
+                        Err(e) => {
+                            error!("Unable to parse upacket: {:?}", e);
+                            Incoming::Unreadable(r_uid.clone(), e)
+                        }
+                    };
+                    if tx.packet(session, incoming).await.is_err() {
+                        error!("Message server stopped, closing connection.");
+                        break;
                     }
                 }
             }
         }
     });
 
-    // Sending task (handles outgoing messages)
-    let send_task = tokio::spawn(async move {
-        while let Some(server_message) = rx.next().await {
-            // convert to UPacket
-            let upacket = make_webpacket(server_message);
-            sender.send(upacket.try_into().unwrap()).await.unwrap();
+    // Sending task (handles outgoing messages, and pings the client while it's quiet)
+    let s_uid = uid.clone();
+    let mut send_task = tokio::spawn(async move {
+        match send_packets(sender, rx, keep_alive, heard).await {
+            Hangup::Closed => {}
+            Hangup::Silent => info!("No word from {} in too long, dropping the connection", s_uid),
+            Hangup::Unwritable => info!("Unable to write to {}, dropping the connection", s_uid),
         }
     });
 
-    // Wait for either task to complete
+    // Wait for either task to complete, then stop the other one: a dropped JoinHandle
+    // leaves its task running, and nothing may come from this session once it's deregistered
     select! {
-        _ = receive_task => info!("Channel closed from receiver end for {}", uid.clone()),
-        _ = send_task => info!("Channel closed from sender end for {}", uid),
+        _ = &mut receive_task => info!("Channel closed from receiver end for {}", uid.clone()),
+        _ = &mut send_task => info!("Channel closed from sender end for {}", uid),
     }
+    receive_task.abort();
+    send_task.abort();
 
-    match server.lock() {
-        Ok(mut s) => {
-            s.deregister(&user_id);
-            info!("Deregistered {:?}", &user_id);
-        }
-        Err(_e) => error!("Unable to unlock server to deregister {:?}", &user_id),
+    match server.deregister(user_id.clone(), session).await {
+        Ok(()) => info!("Deregistered {:?}", &user_id),
+        Err(_e) => error!("Message server stopped before deregistering {:?}", &user_id),
     };
 
     Ok(())
 }
 
+fn start_server<DB: Storage + Send + 'static>(
+    storage: DB,
+    backlog_limits: BacklogLimits,
+    draft_grace: Timestamp,
+    edit_interval: Timestamp,
+    shutdown_drafts: ShutdownDrafts,
+) -> (ServerHandle, ShutdownHandler) {
+    message_server::MessageServer::start(storage, backlog_limits, draft_grace, edit_interval, shutdown_drafts)
+}
+
+// async so the server task is started on Rocket's runtime
 #[launch]
-fn rocket() -> _ {
-    let (s_sender, server, shutdown_server) = message_server::MessageServer::start(MemoryMessageDatabase::new());
-    rocket::build()
+async fn rocket() -> _ {
+    let rocket = rocket::build();
+    // set `sqlite_path` (Rocket.toml or ROCKET_SQLITE_PATH) to keep messages across restarts
+    let sqlite_path: Option<String> = rocket.figment().extract_inner("sqlite_path").ok();
+    // `backlog_max_packets` and `backlog_max_age_secs` bound what's kept for offline users
+    let mut backlog_limits = BacklogLimits::default();
+    if let Ok(max_packets) = rocket.figment().extract_inner("backlog_max_packets") {
+        backlog_limits.max_packets = max_packets;
+    }
+    if let Ok(secs) = rocket.figment().extract_inner::<Timestamp>("backlog_max_age_secs") {
+        backlog_limits.max_age = Some(secs * 1_000_000);
+    }
+    // `draft_grace_secs` is how long a draft waits for its sender to reconnect before it's thrown away
+    let draft_grace = rocket
+        .figment()
+        .extract_inner::<Timestamp>("draft_grace_secs")
+        .map_or(DEFAULT_DRAFT_GRACE, |secs| secs * 1_000_000);
+    // `draft_updates_per_sec` caps how often each draft's changes go out to the people watching it
+    let edit_interval = rocket
+        .figment()
+        .extract_inner::<Timestamp>("draft_updates_per_sec")
+        .map_or(0, |rate| 1_000_000 / rate.max(1));
+    // `ping_interval_secs` (0 turns pings off) and `ping_timeout_secs` decide when a silent connection is dropped
+    let ping_interval = rocket
+        .figment()
+        .extract_inner::<u64>("ping_interval_secs")
+        .unwrap_or(DEFAULT_PING_INTERVAL_SECS);
+    let ping_timeout = rocket
+        .figment()
+        .extract_inner::<u64>("ping_timeout_secs")
+        .unwrap_or(DEFAULT_PING_TIMEOUT_SECS);
+    let keep_alive = (ping_interval > 0).then(|| KeepAlive {
+        interval: Duration::from_secs(ping_interval),
+        timeout: Duration::from_secs(ping_timeout),
+    });
+    // `shutdown_drafts` ("send" or "discard") is what happens to drafts still being typed on shutdown
+    let shutdown_drafts: ShutdownDrafts = rocket.figment().extract_inner("shutdown_drafts").unwrap_or_default();
+    let (server, shutdown_server) = match sqlite_path {
+        Some(path) => {
+            info!("Storing messages in {}", path);
+            let storage = SqliteMessageDatabase::open(&path).expect("Unable to open sqlite storage");
+            start_server(storage, backlog_limits, draft_grace, edit_interval, shutdown_drafts)
+        }
+        None => start_server(MemoryMessageDatabase::new(), backlog_limits, draft_grace, edit_interval, shutdown_drafts),
+    };
+    rocket
         .attach(shutdown_server)
-        .manage(ServerSender(s_sender))
         .manage(server)
-        .mount("/", routes![index, updates])
+        .manage(keep_alive)
+        .mount("/", routes![index, register, login, logout, history, updates])
 }
@@ -1,309 +1,1190 @@
-use crate::identity::UserId;
-use crate::packet::{Destination, Packet, RoutingInfo, SPacket, get_current_time, make_uuid};
-use crate::protocol::{Draft, MessageId, Timestamp};
-use crate::storage::{MessageDAOError, MessageRoomDAO, MessagesDAO, RoomId};
+use crate::outgoing::{self, CloseReason, OutgoingReceiver, OutgoingSender, QueueError};
+use crate::identity::{make_group_chat_id, make_session_id, make_user_id, GroupChatId, SessionId, UserId};
+use crate::packet::{
+    Destination, ErrorCode, MessageRecord, Packet, PacketError, ReceiptRecord, RequestId, RoutingInfo, SPacket, Seq,
+    get_current_time, make_uuid,
+};
+use crate::protocol::{
+    DeltaError, Draft, DraftVisibility, EditOp, Keystroke, MessageId, PresenceStatus, ReceiptStatus, Revision, Timestamp,
+};
+use crate::storage::query::{Cursor, MessageQuery, Page};
+use crate::storage::{AccountDAO, MessageDAOError, MessageRoomDAO, RoomId, Storage};
 use rocket::fairing::{Fairing, Info};
-use rocket::futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::tokio::runtime::Handle;
+use rocket::tokio::task::{self, JoinHandle};
+use rocket::tokio::time::timeout;
 use rocket::{Orbit, Rocket};
-use std::collections::{HashMap, VecDeque};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::panic;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex, mpsc};
-use std::thread::JoinHandle;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Most messages a single SyncRequest can return
+const MAX_SYNC_PAGE: usize = 200;
+/// Most unacknowledged packets kept per connection for replaying on resume
+const MAX_UNACKED: usize = 1000;
+/// Most packets waiting to be written to one connection. A client that falls this far behind is
+/// disconnected, and can resume once it's caught up. Leaves room to replay a whole outbox on resume.
+const MAX_QUEUED: usize = 2 * MAX_UNACKED;
+/// Most closed connections per user that can still be resumed
+const MAX_SUSPENDED: usize = 4;
+/// How long (in microseconds) a draft outlives the connection it was typed on, by default
+pub const DEFAULT_DRAFT_GRACE: Timestamp = 60 * 1_000_000;
+/// Fastest a replay can go, as a percentage of the original pace
+const MAX_REPLAY_SPEED: u32 = 10_000;
+/// Most replays one connection can have playing at once
+const MAX_REPLAYS: usize = 4;
+/// Most commands waiting for the server before whoever sends the next one has to wait too
+const COMMAND_BUFFER: usize = 1024;
+/// How long (in microseconds) connections get to close once the server is told to stop
+const SHUTDOWN_GRACE: Timestamp = 3 * 1_000_000;
+
+/// What a connection hands the server
+#[derive(Debug)]
+pub enum Incoming {
+    /// A packet, with the request id the client gave it (if any)
+    Packet(SPacket, Option<RequestId>),
+    /// Something a user sent that couldn't be read
+    Unreadable(UserId, PacketError),
+}
+
+type AccountsJob = Box<dyn FnOnce(&mut dyn AccountDAO) + Send>;
+
+/// Something for the server task to do. Commands that need an answer carry where to send it.
+enum Command {
+    Register {
+        uid: UserId,
+        resume: Option<(SessionId, Seq)>,
+        reply: oneshot::Sender<Result<(SessionId, OutgoingReceiver), ServerError>>,
+    },
+    Deregister {
+        uid: UserId,
+        session: SessionId,
+    },
+    /// What arrived on a connection
+    Packet(SessionId, Incoming),
+    History {
+        room_id: RoomId,
+        query: MessageQuery,
+        page: Page,
+        reply: oneshot::Sender<Result<Vec<MessageRecord>, ServerError>>,
+    },
+    /// Run something against account & login token storage, see auth
+    Accounts(AccountsJob),
+    /// Finish up and end the server task
+    Stop,
+}
+
+/// How everything else talks to the running server. Cheap to clone; every clone reaches the same task.
+#[derive(Clone)]
+pub struct ServerHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+/// How much is kept for users while they're offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BacklogLimits {
+    /// Most packets waiting per user; older ones are dropped first
+    pub max_packets: usize,
+    /// Packets older than this (in microseconds) are dropped instead of delivered
+    pub max_age: Option<Timestamp>,
+}
+
+impl Default for BacklogLimits {
+    fn default() -> Self {
+        BacklogLimits {
+            max_packets: 1000,
+            // a month
+            max_age: Some(30 * 24 * 60 * 60 * 1_000_000),
+        }
+    }
+}
+
+/// What happens to drafts still being typed when the server stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownDrafts {
+    /// Send them as they are, as if their senders had finished them
+    Send,
+    /// Throw them away, telling whoever was watching
+    #[default]
+    Discard,
+}
+
+/// A packet on its way to a client, numbered within its connection
+pub type Outgoing = (Seq, SPacket);
+
+/// Everything sent on one connection that the client hasn't acknowledged yet
+#[derive(Debug, Default)]
+struct Outbox {
+    next_seq: Seq,
+    unacked: VecDeque<Outgoing>,
+}
+
+/// An open connection
+#[derive(Debug)]
+struct Connection {
+    tx: OutgoingSender,
+    outbox: Outbox,
+    /// The client said nobody is using it, see Packet::Heartbeat
+    idle: bool,
+}
+
+/// A draft being typed right now. Only the connection that started it may change it.
+#[derive(Debug)]
+struct LiveDraft {
+    owner: SessionId,
+    /// What the sender wanted others to see when they started it
+    visibility: DraftVisibility,
+    /// When its connection closed, if it has. The next connection of the sender picks it back up;
+    /// if none does within the grace period, it's thrown away.
+    orphaned: Option<Timestamp>,
+    /// When watchers were last sent a change to it
+    last_broadcast: Timestamp,
+    /// Set while changes are held back from watchers, to when the newest content goes out
+    flush_at: Option<Timestamp>,
+    draft: Draft,
+}
+
+/// What to do with a change to a draft, see LiveDraft::pace
+#[derive(Debug, PartialEq, Eq)]
+enum Pace {
+    /// Send it on as it is
+    Relay,
+    /// Earlier changes were held back, so send the whole content instead
+    Snapshot,
+    /// Too soon after the last one; it goes out with the next flush
+    Hold,
+}
 
 pub struct MessageServer<DB> {
-    open_senders: HashMap<UserId, UnboundedSender<SPacket>>,
-    backlog: HashMap<UserId, VecDeque<SPacket>>,
-    current_drafts: HashMap<(UserId, Destination), Draft>,
+    /// Every open connection, per user. One user can be connected from several places at once.
+    open_senders: HashMap<UserId, HashMap<SessionId, Connection>>,
+    /// Recently closed connections that can still be resumed, oldest first
+    suspended: HashMap<UserId, VecDeque<(SessionId, Outbox)>>,
+    backlog_limits: BacklogLimits,
+    /// How long drafts are kept after their connection closes
+    draft_grace: Timestamp,
+    /// Least time between changes to one draft going out to watchers. 0 sends every change right away
+    edit_interval: Timestamp,
+    shutdown_drafts: ShutdownDrafts,
+    /// Users who may have packets waiting in storage since they last connected
+    queued_for: HashSet<UserId>,
+    /// When each user was last active, or last disconnected. Only kept since the server started
+    last_seen: HashMap<UserId, Timestamp>,
+    /// Who subscribed to each user's presence, on top of the people they talk to
+    presence_watchers: HashMap<UserId, HashSet<UserId>>,
+    current_drafts: HashMap<(UserId, Destination), LiveDraft>,
+    /// Packets to send to one connection later on (replays), keyed by (when, order scheduled)
+    scheduled: BTreeMap<(Timestamp, u64), (UserId, SessionId, SPacket)>,
+    scheduled_count: u64,
     storage: DB,
 }
 
 #[derive(Debug)]
 pub enum ServerError {
-    AlreadyInUse(UserId),
-    TrySendError(UserId),
     DAOError(MessageDAOError),
     MissingDraft((UserId, Destination)),
+    /// The draft was started from another of the user's connections
+    NotDraftOwner((UserId, Destination)),
     /// (expected, received)
-    BadEndDraft(MessageId, MessageId)
+    BadEndDraft(MessageId, MessageId),
+    /// Clients may not send packets that only the server produces
+    ServerOnlyPacket(UserId),
+    NotAMember(UserId, GroupChatId),
+    /// Group membership packets sent somewhere that isn't a group
+    NotAGroup(Destination),
+    /// Only the sender of a message may do that to it
+    NotAuthor(UserId, MessageId),
+    /// A delta that couldn't be applied to the draft
+    BadDelta(MessageId, DeltaError),
+    /// The connection already has as many replays playing as it may
+    TooManyReplays(SessionId),
+    /// The packet came in on a connection that's already closed
+    NotConnected(UserId, SessionId),
+    /// A SyncRequest for no messages, which could never page back through anything
+    EmptySyncRequest,
+    /// The server task isn't running anymore
+    Stopped,
 }
 
-impl<DB: Send + 'static + MessagesDAO> MessageServer<DB> {
+impl<DB: Send + 'static + Storage> MessageServer<DB> {
     pub fn new(storage: DB) -> Self {
         MessageServer {
-            backlog: HashMap::new(),
+            backlog_limits: BacklogLimits::default(),
+            draft_grace: DEFAULT_DRAFT_GRACE,
+            edit_interval: 0,
+            shutdown_drafts: ShutdownDrafts::default(),
+            queued_for: HashSet::new(),
+            last_seen: HashMap::new(),
+            presence_watchers: HashMap::new(),
             open_senders: HashMap::new(),
+            suspended: HashMap::new(),
             current_drafts: HashMap::new(),
+            scheduled: BTreeMap::new(),
+            scheduled_count: 0,
             storage,
         }
     }
-    pub fn start(storage: DB) -> (Sender<SPacket>, Arc<Mutex<Self>>, ShutdownHandler) {
-        let server = Self::new(storage);
-        let server = Arc::new(Mutex::new(server));
-        let server2 = Arc::clone(&server);
-        let (tx, rx) = mpsc::channel();
-        println!("Server started!");
-        let handle = std::thread::spawn(move || {
-            for spacket in rx {
-                let mut s = server.lock().unwrap();
-                match s.process_message(spacket) {
-                    Ok(_sent) => {}
-                    Err(e) => {
-                        error!("Error occurred while sending message: {e:?}")
-                    }
-                };
+    pub fn start(
+        storage: DB,
+        backlog_limits: BacklogLimits,
+        draft_grace: Timestamp,
+        edit_interval: Timestamp,
+        shutdown_drafts: ShutdownDrafts,
+    ) -> (ServerHandle, ShutdownHandler) {
+        let server = MessageServer {
+            backlog_limits,
+            draft_grace,
+            edit_interval,
+            shutdown_drafts,
+            ..Self::new(storage)
+        };
+        let (tx, rx) = mpsc::channel(COMMAND_BUFFER);
+        // storage calls block (sqlite), so the server gets a thread of its own rather than holding up
+        // one of the runtime's workers, and uses the runtime from there for its timers
+        let runtime = Handle::current();
+        let task = task::spawn_blocking(move || runtime.block_on(server.run(rx)));
+        let handle = ServerHandle { commands: tx };
+        (handle.clone(), ShutdownHandler::new(handle, task))
+    }
+
+    /// The server task: owns the server and works through commands one at a time
+    /// until it's told to stop or every handle is gone.
+    /// Once told to stop, it winds down (see shut_down) and keeps going until every connection has
+    /// closed or SHUTDOWN_GRACE is up. Meanwhile nobody new may connect, and packets from
+    /// the closing connections are ignored.
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        info!("Server started!");
+        // when connections stop getting to close, once stopping
+        let mut stopping: Option<Timestamp> = None;
+        loop {
+            // wake up for scheduled packets and expiring drafts even if nothing comes in
+            let received = match self.next_due().into_iter().chain(stopping).min() {
+                Some(due) => {
+                    let wait = Duration::from_micros(due.saturating_sub(get_current_time()));
+                    timeout(wait, commands.recv()).await.ok()
+                }
+                None => Some(commands.recv().await),
+            };
+            match received {
+                Some(None) => break,
+                Some(Some(Command::Stop)) if stopping.is_none() => {
+                    info!("Server stopping");
+                    self.shut_down();
+                    stopping = Some(get_current_time() + SHUTDOWN_GRACE);
+                }
+                Some(Some(Command::Register { reply, .. })) if stopping.is_some() => {
+                    let _ = reply.send(Err(ServerError::Stopped));
+                }
+                Some(Some(Command::Packet(..))) if stopping.is_some() => {}
+                Some(Some(command)) => self.execute(command),
+                // timed out
+                None => {}
             }
-        });
-        (tx, server2, ShutdownHandler::new(handle))
+            if stopping.is_some_and(|until| self.open_senders.is_empty() || until <= get_current_time()) {
+                break;
+            }
+            self.run_due(get_current_time());
+        }
+        if stopping.is_none() {
+            self.shut_down();
+        }
+        info!("Server stopped");
     }
-    pub fn register(&mut self, uid: UserId) -> Result<UnboundedReceiver<SPacket>, ServerError> {
-        // reject if this user is already connected
-        if self.open_senders.contains_key(&uid) {
-            return Err(ServerError::AlreadyInUse(uid));
+
+    /// Wind down before the server task ends. Drafts are sent or thrown away (see ShutdownDrafts),
+    /// and every connection is closed once it's been sent what's already queued for it.
+    /// Sessions can't be resumed after a restart, so packets that have to reach their recipients
+    /// but that a connection hasn't acknowledged yet are put in the backlog too.
+    fn shut_down(&mut self) {
+        let now = get_current_time();
+        self.scheduled.clear();
+        let drafts: Vec<((UserId, Destination), LiveDraft)> = self.current_drafts.drain().collect();
+        for ((sender, destination), live) in drafts {
+            match self.shutdown_drafts {
+                ShutdownDrafts::Send => {
+                    let uuid = live.draft.id;
+                    self.finish_draft(&sender, &destination, live, None, now).unwrap_or_else(|err| {
+                        warn!("Unable to send draft {} of {:?} on shutdown: {:?}", uuid, &sender, err);
+                    });
+                }
+                ShutdownDrafts::Discard => self.discard_draft(&sender, &destination, &live, now),
+            }
         }
-        // create channel, connect them
-        let (tx, rx) = rocket::futures::channel::mpsc::unbounded();
-        self.open_senders.insert(uid.clone(), tx);
+        let mut owed: Vec<(UserId, SPacket)> = vec![];
+        for (uid, sessions) in self.open_senders.iter() {
+            let mut packets: Vec<SPacket> = vec![];
+            // their connections mostly got the same packets
+            for (_, p) in sessions.values().flat_map(|connection| connection.outbox.unacked.iter()) {
+                if let Some(p) = self.owed(p).filter(|p| !packets.contains(p)) {
+                    packets.push(p);
+                }
+            }
+            packets.sort_by_key(|p| p.time);
+            owed.extend(packets.into_iter().map(|p| (uid.clone(), p)));
+        }
+        for (uid, p) in owed {
+            self.enqueue(uid, p);
+        }
+        for connection in self.open_senders.values().flat_map(HashMap::values) {
+            connection.tx.close(CloseReason::Stopping);
+        }
+    }
 
-        // catch them up
-        self.flush_backlog(&uid)?;
+    /// What to keep for a connection that hadn't acknowledged `p` when the server stopped, if anything.
+    /// The draft an EndDraft ended is gone after a restart, so it becomes the message that was stored.
+    fn owed(&self, p: &SPacket) -> Option<SPacket> {
+        match &p.packet {
+            Packet::EndDraft { uuid, .. } => {
+                let room_id = RoomId::from((p.sender.clone(), p.destination.clone()));
+                let message = self.storage.get_room(&room_id).ok()?.get_message(*uuid)?;
+                if message.deleted.is_some() {
+                    return None;
+                }
+                Some(SPacket {
+                    packet: Packet::NewMessage {
+                        uuid: *uuid,
+                        content: message.content.clone(),
+                        start_time: message.start_time,
+                        end_time: message.end_time,
+                        reply_to: message.reply_to,
+                    },
+                    ..p.clone()
+                })
+            }
+            packet if must_arrive(packet) => Some(p.clone()),
+            _ => None,
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Register { uid, resume, reply } => {
+                // if they've gone already, deregistering is left to the connection
+                let _ = reply.send(self.register(uid, resume));
+            }
+            Command::Deregister { uid, session } => self.deregister(&uid, session),
+            Command::Packet(session, incoming) => self.handle(session, incoming),
+            Command::History { room_id, query, page, reply } => {
+                let _ = reply.send(self.history(&room_id, &query, &page));
+            }
+            Command::Accounts(run) => run(&mut self.storage),
+            Command::Stop => {}
+        }
+    }
+
+    /// Open a new connection for `uid`, alongside any they already have.
+    /// A client that lost its connection can pass `resume` with the old session and the last
+    /// sequence number it acknowledged to pick up where it left off, with the same session id.
+    pub fn register(
+        &mut self,
+        uid: UserId,
+        resume: Option<(SessionId, Seq)>,
+    ) -> Result<(SessionId, OutgoingReceiver), ServerError> {
+        // create channel, connect them
+        let (tx, rx) = outgoing::channel(MAX_QUEUED);
+        let before = self.presence_status(&uid);
+        if let Some((session, _)) = resume {
+            // the old connection may not have noticed it's dead yet
+            self.close_connection(&uid, session);
+        }
+        let resumed = resume.and_then(|(session, acked)| {
+            let mut outbox = self.take_suspended(&uid, session)?;
+            outbox.ack(acked);
+            outbox.is_complete_after(acked).then_some((session, outbox))
+        });
+        let (session, outbox, is_resumed) = match resumed {
+            Some((session, outbox)) => (session, outbox, true),
+            None => (make_session_id(), Outbox::default(), false),
+        };
+        // whatever the old connection never confirmed goes out again, with the same numbers
+        for out in outbox.unacked.iter() {
+            tx.send(out.clone()).unwrap_or_else(|err| {
+                warn!("Unable to replay to {:?}: {:?}", &uid, err);
+            });
+        }
+        self.open_senders
+            .entry(uid.clone())
+            .or_default()
+            .insert(session, Connection { tx, outbox, idle: false });
+        info!("{:?} connected with session {:?} (resumed: {})", &uid, session, is_resumed);
         let time = get_current_time();
+        self.last_seen.insert(uid.clone(), time);
+        self.reply(&uid, session, SPacket {
+            sender: uid.clone(),
+            destination: Destination::User(uid.clone()),
+            time,
+            packet: Packet::Welcome {
+                session: session.to_uuid(),
+                resumed: is_resumed,
+            },
+        });
 
-        for ((sender, dest), draft) in self.current_drafts.iter() {
-            match dest {
-                Destination::User(to) => {
-                    if to == &uid {
-                        info!("Catching up user {:?}", to);
-                        // this is the one we just created
-                        if let Some(tx) = self.open_senders.get(&uid) {
-                            tx.unbounded_send(SPacket {
-                                sender: sender.clone(),
-                                destination: dest.clone(),
-                                time,
-                                packet: Packet::NewDraft {
-                                    uuid: draft.id,
-                                    start_time: draft.start_time,
-                                },
-                            })
-                            .unwrap_or_else(|t| {
-                                warn!(
-                                    "Could not resend draft to newly registered user {:?}: {:?}",
-                                    &uid, t
-                                );
-                            });
-                            tx.unbounded_send(SPacket {
-                                sender: sender.clone(),
-                                destination: dest.clone(),
-                                time,
-                                packet: Packet::Edit {
-                                    uuid: draft.id,
-                                    content: draft.content.clone(),
-                                    editing_draft: true,
-                                },
-                            })
-                            .unwrap_or_else(|t| {
-                                warn!(
-                                    "Could not resend draft to newly registered user {:?}: {:?}",
-                                    &uid, t
-                                );
-                            });
-                        }
-                    }
-                }
+        // drafts left behind by a connection that dropped carry on here
+        for ((sender, _), live) in self.current_drafts.iter_mut() {
+            if sender == &uid && live.orphaned.is_some() {
+                live.owner = session;
+                live.orphaned = None;
+            }
+        }
+
+        // catch them up, including anything queued before a restart
+        self.queued_for.insert(uid.clone());
+        self.flush_backlog(&uid)?;
+        let mut catch_up = vec![];
+        for ((sender, dest), live) in self.current_drafts.iter() {
+            // drafts to them, and their own drafts from their other connections
+            let own = sender == &uid;
+            if !own && (live.visibility == DraftVisibility::Hidden || !self.recipients(sender, dest).contains(&uid)) {
+                continue;
             }
+            info!("Catching up user {:?}", &uid);
+            catch_up.push(SPacket {
+                sender: sender.clone(),
+                destination: dest.clone(),
+                time,
+                packet: Packet::NewDraft {
+                    uuid: live.draft.id,
+                    start_time: live.draft.start_time,
+                    reply_to: live.draft.reply_to,
+                },
+            });
+            if !own && live.visibility == DraftVisibility::Typing {
+                continue;
+            }
+            catch_up.push(SPacket {
+                sender: sender.clone(),
+                destination: dest.clone(),
+                time,
+                packet: Packet::Edit {
+                    uuid: live.draft.id,
+                    content: live.draft.content.clone(),
+                    editing_draft: true,
+                    revision: live.draft.revision,
+                },
+            });
+        }
+        for p in catch_up {
+            self.reply(&uid, session, p);
         }
+        self.announce_presence(&uid, before, time);
 
         // give the receiver so they can talk to the server
-        Ok(rx)
+        Ok((session, rx))
+    }
+
+    /// Removes a closed connection's outbox so it can be resumed
+    fn take_suspended(&mut self, uid: &UserId, session: SessionId) -> Option<Outbox> {
+        let suspended = self.suspended.get_mut(uid)?;
+        let index = suspended.iter().position(|(s, _)| *s == session)?;
+        let (_, outbox) = suspended.remove(index)?;
+        if suspended.is_empty() {
+            self.suspended.remove(uid);
+        }
+        Some(outbox)
+    }
+
+    /// Close one of `uid`'s connections. The drafts typed on it are kept for their next connection
+    /// to pick up, and only thrown away if that doesn't happen within the grace period.
+    pub fn deregister(&mut self, uid: &UserId, session: SessionId) {
+        let before = self.presence_status(uid);
+        self.close_connection(uid, session);
+        let now = get_current_time();
+        if before != PresenceStatus::Offline && !self.open_senders.contains_key(uid) {
+            self.last_seen.insert(uid.clone(), now);
+            // subscriptions only last while they're connected
+            self.presence_watchers.retain(|_, watchers| {
+                watchers.remove(uid);
+                !watchers.is_empty()
+            });
+        }
+        self.announce_presence(uid, before, now);
     }
 
-    pub fn deregister(&mut self, uid: &UserId) {
+    /// Deregistering without telling anyone, for a connection that's about to be replaced
+    fn close_connection(&mut self, uid: &UserId, session: SessionId) {
         // make sure they're disconnected so we can't send anything to them
-        self.open_senders.remove(uid);
+        if let Some(sessions) = self.open_senders.get_mut(uid) {
+            if let Some(connection) = sessions.remove(&session) {
+                // keep what they might not have gotten in case they come back
+                let suspended = self.suspended.entry(uid.clone()).or_default();
+                suspended.push_back((session, connection.outbox));
+                if suspended.len() > MAX_SUSPENDED {
+                    suspended.pop_front();
+                }
+            }
+            if sessions.is_empty() {
+                self.open_senders.remove(uid);
+            }
+        }
+
+        // replays stop with the connection watching them
+        self.scheduled.retain(|_, (_, s, _)| *s != session);
 
-        // remove all their drafted messages (not saving them)
-        // and notify the clients they were sending them to
-        let mut drafts_to_remove = vec![];
         let current_time: Timestamp = get_current_time();
-        for ((sender, dest), draft) in self.current_drafts.iter() {
-            if sender == uid {
-                drafts_to_remove.push((sender.clone(), dest.clone()));
-                match dest {
-                    Destination::User(to) => {
-                        if let Some(tx) = self.open_senders.get(to) {
-                            tx.unbounded_send(SPacket {
-                                sender: uid.clone(),
-                                destination: dest.clone(),
-                                time: current_time,
-                                packet: Packet::DiscardDraft { uuid: draft.id },
-                            })
-                            .unwrap_or_else(|err| {
-                                warn!(
-                                    "Unable to send DiscardDraft packet to receiver {:?}: {:?}",
-                                    to, err
-                                )
-                            });
-                        }
-                    }
-                }
+        for ((sender, _), live) in self.current_drafts.iter_mut() {
+            if sender == uid && live.owner == session && live.orphaned.is_none() {
+                live.orphaned = Some(current_time);
+            }
+        }
+        // with no grace period, they go right away
+        self.discard_orphaned(current_time);
+    }
+
+    /// Remove the drafts nobody came back for within the grace period (not saving them)
+    /// and notify the clients they were sending them to
+    fn discard_orphaned(&mut self, now: Timestamp) {
+        let grace = self.draft_grace;
+        let drafts_to_remove: Vec<(UserId, Destination)> = self.current_drafts
+            .iter()
+            .filter(|(_, live)| live.orphaned.is_some_and(|since| since.saturating_add(grace) <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (sender, dest) in drafts_to_remove {
+            if let Some(live) = self.current_drafts.remove(&(sender.clone(), dest.clone())) {
+                info!("Discarding draft {} left behind by {:?}", live.draft.id, &sender);
+                self.discard_draft(&sender, &dest, &live, now);
+            }
+        }
+    }
+
+    /// Stored messages of a room matching `query`, oldest first
+    pub fn history(
+        &self,
+        room_id: &RoomId,
+        query: &MessageQuery,
+        page: &Page,
+    ) -> Result<Vec<MessageRecord>, ServerError> {
+        let room = self.storage.get_room(room_id)?;
+        Ok(room
+            .get_messages(query, page)?
+            .into_iter()
+            .map(MessageRecord::from)
+            .collect())
+    }
+
+    /// The room a sent message is in, as long as `editor` is the one who sent it
+    fn authored_room(&mut self, editor: &UserId, room_id: &RoomId, uuid: MessageId) -> Result<&mut DB::RoomDAO, ServerError> {
+        let room = self.storage.get_room_mut(room_id)?;
+        let author = &room.get_message(uuid).ok_or(MessageDAOError::MissingMessageId(uuid))?.sender;
+        if author != editor {
+            return Err(ServerError::NotAuthor(editor.clone(), uuid));
+        }
+        Ok(room)
+    }
+
+    /// Fails unless `parent` is a message in the room, so it can be replied to
+    fn check_parent(&self, room_id: &RoomId, parent: MessageId) -> Result<(), ServerError> {
+        let missing = MessageDAOError::MissingMessageId(parent);
+        match self.storage.get_room(room_id) {
+            Ok(room) if room.get_message(parent).is_some() => Ok(()),
+            // a conversation that hasn't started has nothing to reply to
+            Ok(_) | Err(MessageDAOError::MissingRoomId(_)) => Err(missing.into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Change what a sent message says, returning its new version
+    fn edit_sent_message(
+        &mut self,
+        editor: &UserId,
+        room_id: &RoomId,
+        uuid: MessageId,
+        content: String,
+        time: Timestamp,
+    ) -> Result<Revision, ServerError> {
+        Ok(self.authored_room(editor, room_id, uuid)?.edit_message(uuid, content, time)?)
+    }
+
+    /// Everyone other than `sender` taking part in the conversation at `destination`
+    fn recipients(&self, sender: &UserId, destination: &Destination) -> Vec<UserId> {
+        match destination {
+            Destination::User(uid) => vec![uid.clone()],
+            Destination::Group(gc_id) => self.storage
+                .get_room(&RoomId::Group(gc_id.clone()))
+                .map(|room| room.members().iter().filter(|m| *m != sender).cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Who else gets to know about a draft at all, given how visible it is
+    fn draft_watchers(&self, sender: &UserId, destination: &Destination, visibility: DraftVisibility) -> Vec<UserId> {
+        match visibility {
+            DraftVisibility::Hidden => vec![],
+            DraftVisibility::Live | DraftVisibility::Typing => self.recipients(sender, destination),
+        }
+    }
+
+    /// How `sender` wants their drafts shown in the conversation at `destination`
+    fn draft_visibility(&self, sender: &UserId, destination: &Destination) -> Result<DraftVisibility, ServerError> {
+        let chosen = match self.storage.draft_visibility(sender, Some(destination))? {
+            Some(visibility) => Some(visibility),
+            None => self.storage.draft_visibility(sender, None)?,
+        };
+        Ok(chosen.unwrap_or_default())
+    }
+
+    /// Hands `msg` to every connection `to` has open.
+    /// If none of them took it, it's given back so it can be queued.
+    fn try_send(&mut self, to: &UserId, msg: SPacket) -> Result<Option<SPacket>, ServerError> {
+        self.send_except(to, None, msg)
+    }
+
+    /// Like try_send, but skips one connection (usually the one the packet came from)
+    fn send_except(
+        &mut self,
+        to: &UserId,
+        skip: Option<SessionId>,
+        msg: SPacket,
+    ) -> Result<Option<SPacket>, ServerError> {
+        let mut delivered = false;
+        let sessions = self.open_senders.get_mut(to).into_iter().flatten();
+        for (session, connection) in sessions.filter(|(session, _)| Some(**session) != skip) {
+            match connection.send(msg.clone()) {
+                Ok(_) => delivered = true,
+                // the connection is cleaned up after processing
+                Err(QueueError::Closed) => {}
+                Err(QueueError::Full) => warn!("{:?} fell too far behind on {:?}, disconnecting", to, session),
+            }
+        }
+        Ok(if delivered { None } else { Some(msg) })
+    }
+
+    /// Sends `msg` to each of `recipients`, queueing it for whoever isn't connected
+    fn send_or_enqueue(&mut self, recipients: Vec<UserId>, msg: SPacket) -> Result<(), ServerError> {
+        for to in recipients {
+            if let Some(p) = self.try_send(&to, msg.clone())? {
+                self.enqueue(to, p);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer one particular connection
+    fn reply(&mut self, to: &UserId, session: SessionId, msg: SPacket) {
+        if let Some(connection) = self.open_senders.get_mut(to).and_then(|s| s.get_mut(&session)) {
+            connection.send(msg).unwrap_or_else(|err| {
+                warn!("Unable to reply to {:?}: {:?}", to, err);
+            });
+        }
+    }
+
+    /// Sends `packet` to everyone in `recipients`, returning who couldn't be reached.
+    fn broadcast(
+        &mut self,
+        recipients: &[UserId],
+        sender: &UserId,
+        destination: &Destination,
+        time: Timestamp,
+        packet: &Packet,
+    ) -> Result<Vec<UserId>, ServerError> {
+        let mut missed = vec![];
+        for to in recipients {
+            let p = SPacket {
+                sender: sender.clone(),
+                destination: destination.clone(),
+                time,
+                packet: packet.clone(),
+            };
+            if self.try_send(to, p)?.is_some() {
+                missed.push(to.clone());
+            }
+        }
+        Ok(missed)
+    }
+
+    /// Send `msg` to one connection once it's `at`. If the connection is gone by then, it's dropped.
+    fn schedule(&mut self, at: Timestamp, to: &UserId, session: SessionId, msg: SPacket) {
+        self.scheduled.insert((at, self.scheduled_count), (to.clone(), session, msg));
+        self.scheduled_count += 1;
+    }
+
+    /// When the next scheduled packet should go out, held back draft changes are flushed,
+    /// or the next left-behind draft expires
+    pub fn next_due(&self) -> Option<Timestamp> {
+        let packet = self.scheduled.keys().next().map(|(at, _)| *at);
+        let flush = self.current_drafts.values().filter_map(|live| live.flush_at).min();
+        let draft = self.current_drafts
+            .values()
+            .filter_map(|live| live.orphaned)
+            .min()
+            .map(|since| since.saturating_add(self.draft_grace));
+        packet.into_iter().chain(flush).chain(draft).min()
+    }
+
+    /// Send every scheduled packet and held back draft change that's due by `now`,
+    /// and throw away drafts that have expired
+    pub fn run_due(&mut self, now: Timestamp) {
+        while let Some(entry) = self.scheduled.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (to, session, msg) = entry.remove();
+            self.reply(&to, session, msg);
+        }
+        self.flush_drafts(now);
+        self.discard_orphaned(now);
+    }
+
+    /// Send the newest content of every draft whose changes were held back long enough
+    fn flush_drafts(&mut self, now: Timestamp) {
+        let due: Vec<(UserId, Destination)> = self.current_drafts
+            .iter()
+            .filter(|(_, live)| live.flush_at.is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (sender, dest) in due {
+            let Some(live) = self.current_drafts.get_mut(&(sender.clone(), dest.clone())) else {
+                continue;
+            };
+            live.flush_at = None;
+            live.last_broadcast = now;
+            let (owner, visibility) = (live.owner, live.visibility);
+            let edit = Packet::Edit {
+                uuid: live.draft.id,
+                content: live.draft.content.clone(),
+                editing_draft: true,
+                revision: live.draft.revision,
+            };
+            let watchers = match visibility {
+                DraftVisibility::Live => self.recipients(&sender, &dest),
+                DraftVisibility::Typing | DraftVisibility::Hidden => vec![],
+            };
+            let sent = self
+                .broadcast(&watchers, &sender, &dest, now, &edit)
+                .and_then(|_| self.send_except(&sender, Some(owner), SPacket {
+                    sender: sender.clone(),
+                    destination: dest.clone(),
+                    time: now,
+                    packet: edit,
+                }));
+            if let Err(err) = sent {
+                warn!("Unable to flush draft of {:?}: {:?}", &sender, err);
             }
         }
+    }
 
-        for k in drafts_to_remove {
-            self.current_drafts.remove(&k);
+    /// Turn a draft into a sent message with `content` (or what it says now), storing it and
+    /// ending it for everyone who could see it
+    fn finish_draft(
+        &mut self,
+        sender: &UserId,
+        destination: &Destination,
+        live: LiveDraft,
+        content: Option<String>,
+        time: Timestamp,
+    ) -> Result<(), ServerError> {
+        let LiveDraft { mut draft, visibility, flush_at, .. } = live;
+        let uuid = draft.id;
+        let recipients = self.recipients(sender, destination);
+        // the final content wins over whatever the last edit said
+        if let Some(content) = content.clone().filter(|c| *c != draft.content) {
+            draft.set_content(content, time);
+        }
+        // nobody has seen the changes that were held back, so they get the final content now
+        let content = match flush_at {
+            Some(_) => Some(draft.content.clone()),
+            None => content,
+        };
+        // nobody watched a hidden or typing-only draft being typed, so it can't be replayed either
+        let timeline = match visibility {
+            DraftVisibility::Live => std::mem::take(&mut draft.timeline),
+            DraftVisibility::Typing | DraftVisibility::Hidden => vec![],
+        };
+        let reply_to = draft.reply_to;
+        let new_message = Packet::NewMessage {
+            uuid,
+            content: draft.content.clone(),
+            start_time: draft.start_time,
+            end_time: time,
+            reply_to,
+        };
+        // whoever didn't watch it being typed gets all of it now
+        let ending = match visibility {
+            DraftVisibility::Live => Packet::EndDraft { content: content.clone(), uuid, reply_to },
+            DraftVisibility::Typing => Packet::EndDraft { content: Some(draft.content.clone()), uuid, reply_to },
+            DraftVisibility::Hidden => new_message.clone(),
+        };
+        // nobody hears it was sent unless it was kept
+        let room_id = RoomId::from((sender.clone(), destination.clone()));
+        self.storage.add_message(draft.into_message(sender.clone(), time), destination.clone())?;
+        self.storage.get_room_mut(&room_id)?.set_timeline(uuid, timeline)?;
+        self.try_send(sender, SPacket {
+            sender: sender.clone(),
+            destination: destination.clone(),
+            time,
+            packet: Packet::EndDraft { content, uuid, reply_to },
+        })?;
+        let missed = self.broadcast(&recipients, sender, destination, time, &ending)?;
+        for recipient in missed.iter() {
+            self.enqueue(recipient.clone(), SPacket {
+                sender: sender.clone(),
+                destination: destination.clone(),
+                time,
+                packet: new_message.clone(),
+            });
         }
+        for recipient in recipients.iter().filter(|r| !missed.contains(r)) {
+            self.delivered(&room_id, uuid, recipient, time);
+        }
+        Ok(())
+    }
+
+    /// Tell everyone who could see a draft (the sender's other connections too) that it's gone
+    fn discard_draft(&mut self, sender: &UserId, destination: &Destination, live: &LiveDraft, time: Timestamp) {
+        let uuid = live.draft.id;
+        let mut audience = self.draft_watchers(sender, destination, live.visibility);
+        audience.push(sender.clone());
+        self.broadcast(&audience, sender, destination, time, &Packet::DiscardDraft { uuid })
+            .unwrap_or_else(|err| {
+                warn!("Unable to send DiscardDraft packet for {}: {:?}", uuid, err);
+                vec![]
+            });
+    }
+
+    fn enqueue(&mut self, recipient: UserId, p: SPacket) {
+        info!("Queueing message {:?} for {:?}", &p, &recipient);
+        self.storage
+            .push_backlog(&recipient, p, self.backlog_limits.max_packets)
+            .unwrap_or_else(|err| {
+                warn!("Unable to queue packet for {:?}: {:?}", &recipient, err);
+            });
+        self.queued_for.insert(recipient);
     }
 
     fn flush_backlog(&mut self, user_id: &UserId) -> Result<(), ServerError> {
-        if let Some(tx) = self.open_senders.get(user_id) {
-            if let Some(mut backlog) = self.backlog.remove(user_id) {
-                while let Some(msg) = backlog.pop_front() {
-                    match tx.unbounded_send(msg) {
-                        Ok(_) => {}
-                        Err(_e) => return Err(ServerError::TrySendError(user_id.clone())),
-                    }
+        if !self.open_senders.contains_key(user_id) || !self.queued_for.remove(user_id) {
+            return Ok(());
+        }
+        let now = get_current_time();
+        let expired_before = self.backlog_limits.max_age.map_or(0, |age| now.saturating_sub(age));
+        let backlog = self.storage.take_backlog(user_id, expired_before)?;
+        let mut pending = VecDeque::from(backlog.packets);
+        if backlog.dropped > 0 {
+            // let them know there was more than what they're getting
+            pending.push_front(SPacket {
+                sender: user_id.clone(),
+                destination: Destination::User(user_id.clone()),
+                time: now,
+                packet: Packet::Missed { count: backlog.dropped },
+            });
+        }
+        while let Some(msg) = pending.pop_front() {
+            let delivered = match &msg.packet {
+                Packet::NewMessage { uuid, .. } => Some((RoomId::from((msg.sender.clone(), msg.destination.clone())), *uuid)),
+                _ => None,
+            };
+            if let Some(msg) = self.try_send(user_id, msg)? {
+                // they went away again, keep the rest for next time
+                for p in std::iter::once(msg).chain(pending) {
+                    self.enqueue(user_id.clone(), p);
                 }
-                Ok(())
-            } else {
-                Ok(())
+                break;
+            }
+            if let Some((room_id, uuid)) = delivered {
+                self.delivered(&room_id, uuid, user_id, now);
             }
-        } else {
-            Ok(())
         }
+        Ok(())
     }
 
-    pub fn process_message(&mut self, msg: SPacket) -> Result<bool, ServerError> {
-        self.process_message_internal(msg)
+    /// Record how far a message got with `member`, and tell its sender if that's news
+    fn record_receipt(
+        &mut self,
+        room_id: &RoomId,
+        uuid: MessageId,
+        member: &UserId,
+        status: ReceiptStatus,
+        time: Timestamp,
+    ) -> Result<(), ServerError> {
+        let room = self.storage.get_room_mut(room_id)?;
+        let author = room.get_message(uuid).ok_or(MessageDAOError::MissingMessageId(uuid))?.sender.clone();
+        if &author == member || !room.set_receipt(uuid, member, status, time)? {
+            return Ok(());
+        }
+        // as if it came from them, in the conversation the author sent it to
+        let destination = match room_id {
+            RoomId::DM(_) => Destination::User(author.clone()),
+            RoomId::Group(gc_id) => Destination::Group(gc_id.clone()),
+        };
+        self.send_or_enqueue(vec![author], SPacket {
+            sender: member.clone(),
+            destination,
+            time,
+            packet: Packet::Receipt { uuid, status, time },
+        })
     }
 
-    /// Create necessary extra packets to pass messages along to everyone that needs it.
-    /// Also maintain state with storage.
-    fn process_message_internal(&mut self, msg: SPacket) -> Result<bool, ServerError> {
-        // route and re-send it
-        let (to, _from) = msg.get_to_from();
-        let to = match to {
-            Destination::User(uid) => uid,
-        };
-        self.flush_backlog(&to)?; // will only go if sender exists
-        let mut disconnected = false;
-        let mut try_send = |msg: SPacket| {
-            let dest = match &msg.destination {
-                Destination::User(u) => u
+    /// A message reached one of `member`'s connections. Messages that were never stored
+    /// (e.g. relayed NewMessages) don't get receipts.
+    fn delivered(&mut self, room_id: &RoomId, uuid: MessageId, member: &UserId, time: Timestamp) {
+        self.record_receipt(room_id, uuid, member, ReceiptStatus::Delivered, time)
+            .unwrap_or_else(|err| info!("No delivery receipt for {} to {:?}: {:?}", uuid, member, err));
+    }
+
+    /// Whether `uid` is connected, and using any of their connections
+    fn presence_status(&self, uid: &UserId) -> PresenceStatus {
+        match self.open_senders.get(uid) {
+            None => PresenceStatus::Offline,
+            Some(sessions) if sessions.values().all(|connection| connection.idle) => PresenceStatus::Idle,
+            Some(_) => PresenceStatus::Online,
+        }
+    }
+
+    /// The Presence packet describing `uid` right now
+    fn presence(&self, uid: &UserId) -> Packet {
+        let status = self.presence_status(uid);
+        let last_seen = match status {
+            PresenceStatus::Online => None,
+            PresenceStatus::Idle | PresenceStatus::Offline => self.last_seen.get(uid).copied(),
+        };
+        Packet::Presence { status, last_seen }
+    }
+
+    /// If `uid` isn't `before` any more, tell the people they talk to and whoever subscribed.
+    /// Presence is only news while it's current, so it isn't queued for anyone offline.
+    fn announce_presence(&mut self, uid: &UserId, before: PresenceStatus, time: Timestamp) {
+        let packet = self.presence(uid);
+        if matches!(packet, Packet::Presence { status, .. } if status == before) {
+            return;
+        }
+        let mut audience = self.storage.partners(uid);
+        audience.extend(self.presence_watchers.get(uid).into_iter().flatten().cloned());
+        for to in audience {
+            let p = SPacket {
+                sender: uid.clone(),
+                destination: Destination::User(to.clone()),
+                time,
+                packet: packet.clone(),
             };
-            if let Some(tx) = self.open_senders.get(dest) {
-                // then, send the message
-                match tx.unbounded_send(msg) {
-                    Ok(_) => return Ok(None),
-                    Err(e) => {
-                        if e.is_disconnected() {
-                            disconnected = true;
-                            Ok(Some(e.into_inner())) // retrieve the message
-                        } else {
-                            return Err(ServerError::TrySendError(to.clone()));
-                        }
-                    }
-                }
-            } else {
-                Ok(Some(msg))
+            if let Err(err) = self.try_send(&to, p) {
+                warn!("Unable to send presence of {:?} to {:?}: {:?}", uid, &to, err);
+            }
+        }
+    }
+
+    /// Mark one of `uid`'s connections idle or in use, announcing it if that changes their presence
+    fn set_idle(&mut self, uid: &UserId, session: SessionId, idle: bool, time: Timestamp) {
+        let before = self.presence_status(uid);
+        let Some(connection) = self.open_senders.get_mut(uid).and_then(|s| s.get_mut(&session)) else {
+            return;
+        };
+        connection.idle = idle;
+        if !idle {
+            self.last_seen.insert(uid.clone(), time);
+        }
+        self.announce_presence(uid, before, time);
+    }
+
+    /// Tell every member of a group (including ones that just left) who is in it now.
+    fn announce_group(
+        &mut self,
+        gc_id: &GroupChatId,
+        sender: &UserId,
+        also_tell: Option<UserId>,
+        time: Timestamp,
+    ) -> Result<(), ServerError> {
+        let room = self.storage.get_room(&RoomId::Group(gc_id.clone()))?;
+        let mut members: Vec<String> = room.members().iter().map(UserId::to_string).collect();
+        members.sort();
+        let mut audience: Vec<UserId> = room.members().iter().cloned().collect();
+        audience.extend(also_tell);
+        let destination = Destination::Group(gc_id.clone());
+        let packet = Packet::GroupInfo { members };
+        for to in audience {
+            let p = SPacket {
+                sender: sender.clone(),
+                destination: destination.clone(),
+                time,
+                packet: packet.clone(),
+            };
+            if let Some(p) = self.try_send(&to, p)? {
+                self.enqueue(to, p);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget connections whose receiving end has gone away
+    fn drop_closed(&mut self, users: &[UserId]) {
+        for uid in users {
+            let closed: Vec<SessionId> = self.open_senders
+                .get(uid)
+                .into_iter()
+                .flatten()
+                .filter(|(_, connection)| connection.tx.is_closed())
+                .map(|(session, _)| *session)
+                .collect();
+            for session in closed {
+                // if they disconnect, remove the sending channel
+                self.deregister(uid, session);
+            }
+        }
+    }
+
+    /// Handle what arrived on connection `session`. If it fails, the client is sent an Error;
+    /// if it worked and the client gave it a request id, an Accepted.
+    pub fn handle(&mut self, session: SessionId, incoming: Incoming) {
+        let (uid, request_id, outcome) = match incoming {
+            Incoming::Packet(msg, request_id) => {
+                let uid = msg.sender.clone();
+                let outcome = self.process_message(session, msg).map_err(|e| {
+                    error!("Error occurred while handling packet from {:?}: {e:?}", &uid);
+                    (e.code(), e.message_id())
+                });
+                (uid, request_id, outcome)
             }
+            Incoming::Unreadable(uid, e) => (uid, e.request_id(), Err((ErrorCode::BadPacket, None))),
+        };
+        let packet = match outcome {
+            Ok(_) => match request_id {
+                Some(request_id) => Packet::Accepted { request_id },
+                None => return,
+            },
+            Err((code, uuid)) => Packet::Error { code, uuid, request_id },
         };
+        self.reply(&uid, session, SPacket {
+            sender: uid.clone(),
+            destination: Destination::User(uid.clone()),
+            time: get_current_time(),
+            packet,
+        });
+    }
+
+    /// Handle a packet that arrived on connection `session`
+    pub fn process_message(&mut self, session: SessionId, msg: SPacket) -> Result<bool, ServerError> {
+        // stragglers from a dead socket could start drafts no one will ever finish
+        let open = self.open_senders
+            .get(&msg.sender)
+            .and_then(|sessions| sessions.get(&session))
+            .is_some_and(|connection| !connection.tx.is_closed());
+        if !open {
+            return Err(ServerError::NotConnected(msg.sender, session));
+        }
+        let (to, from) = msg.get_to_from();
+        let result = self.process_message_internal(session, msg);
+        let mut involved = self.recipients(&from, &to);
+        involved.push(from);
+        self.drop_closed(&involved);
+        result
+    }
 
+    /// Create necessary extra packets to pass messages along to everyone that needs it.
+    /// Also maintain state with storage.
+    fn process_message_internal(&mut self, session: SessionId, msg: SPacket) -> Result<bool, ServerError> {
         let SPacket {
             sender,
             destination,
             packet,
             time,
         } = msg;
+        if let Destination::Group(gc_id) = &destination {
+            // only members get to talk in (or about) a group
+            self.storage
+                .get_room(&RoomId::Group(gc_id.clone()))?
+                .members()
+                .contains(&sender)
+                .then_some(())
+                .ok_or(ServerError::NotAMember(sender.clone(), gc_id.clone()))?;
+        }
+
         let current_time = get_current_time();
+        match &packet {
+            Packet::Heartbeat { idle } => self.set_idle(&sender, session, *idle, current_time),
+            // acknowledging is done by the client on its own
+            Packet::Ack { .. } => {}
+            _ => self.set_idle(&sender, session, false, current_time),
+        }
+
+        // route and re-send it
+        let recipients = self.recipients(&sender, &destination);
+        for to in recipients.iter() {
+            self.flush_backlog(to)?; // will only go if they're connected
+        }
         let draft_key = (sender.clone(), destination.clone());
-        
-        let mut enqueue = |recipient, p| {
-            info!("Queueing message {:?} for {:?}", &p, &recipient);
-            self.backlog.entry(recipient)
-                .or_default()
-                .push_back(p);
-        };
-        
+
         match packet {
-            Packet::StartDraft => {
+            Packet::StartDraft { reply_to } => {
                 info!("{:?} started a draft", sender.clone());
+                if let Some(parent) = reply_to {
+                    self.check_parent(&draft_key.clone().into(), parent)?;
+                }
                 let uuid = make_uuid();
-                self.current_drafts.insert(
+                let visibility = self.draft_visibility(&sender, &destination)?;
+                let previous = self.current_drafts.insert(
                     draft_key.clone(),
-                    Draft {
-                        content: String::new(),
-                        id: uuid,
-                        start_time: current_time,
+                    LiveDraft {
+                        owner: session,
+                        visibility,
+                        orphaned: None,
+                        last_broadcast: 0,
+                        flush_at: None,
+                        draft: Draft {
+                            content: String::new(),
+                            id: uuid,
+                            start_time: current_time,
+                            revision: 0,
+                            timeline: vec![],
+                            reply_to,
+                        },
                     },
                 );
-                try_send(SPacket {
-                    sender: sender.clone(),
-                    destination: destination.clone(),
-                    time: current_time,
-                    packet: Packet::NewDraft {
-                        uuid,
-                        start_time: current_time,
-                    },
-                })?;
+                // starting over (maybe from another device) replaces the old draft
+                if let Some(previous) = previous {
+                    self.discard_draft(&sender, &destination, &previous, current_time);
+                }
+                let new_draft = Packet::NewDraft {
+                    uuid,
+                    start_time: current_time,
+                    reply_to,
+                };
+                let watchers = self.draft_watchers(&sender, &destination, visibility);
+                self.broadcast(&watchers, &sender, &destination, current_time, &new_draft)?;
 
-                // inform sender of their draft's info
-                try_send(SPacket {
-                    sender: sender.clone(),
-                    destination: Destination::User(sender.clone()), // inform sender!
-                    time: current_time,
-                    packet: Packet::NewDraft {
-                        uuid,
-                        start_time: current_time,
-                    },
-                })?;
-            }
-            Packet::EndDraft { content, uuid } => {
-                let p1 = SPacket {
+                // inform sender (on all their connections) of their draft's info
+                self.try_send(&sender, SPacket {
                     sender: sender.clone(),
                     destination: destination.clone(),
                     time: current_time,
-                    packet: Packet::EndDraft {
-                        content: content.clone(),
-                        uuid,
-                    },
-                };
-                try_send(SPacket {
-                    sender: sender.clone(),
-                    destination: Destination::User(sender.clone()),
-                    time: current_time,
-                    packet: Packet::EndDraft { content: content.clone(), uuid },
+                    packet: new_draft,
                 })?;
+            }
+            Packet::EndDraft { content, uuid, reply_to } => {
                 // info!("Current drafts available: {:?}", self.current_drafts);
-                let draft = self.current_drafts.get(&draft_key)
+                let live = self.current_drafts.get(&draft_key)
                     .ok_or(ServerError::MissingDraft(draft_key.clone()))?;
-                if let Some(_p) = try_send(p1)? {
-                    if let Destination::User(recipient) = destination.clone() {
-                        if draft.id != uuid {
-                            Err(ServerError::BadEndDraft(draft.id, uuid))?;
-                        }
-                        enqueue(recipient.clone(), SPacket {
-                            sender: sender.clone(),
-                            destination: destination.clone(),
-                            time: current_time,
-                            packet: Packet::NewMessage {
-                                uuid,
-                                content: content.clone().unwrap_or(draft.content.clone()),
-                                start_time: draft.start_time,
-                                end_time: current_time,
-                            },
-                        })
-                    }
+                if live.owner != session {
+                    Err(ServerError::NotDraftOwner(draft_key.clone()))?;
+                }
+                if live.draft.id != uuid {
+                    Err(ServerError::BadEndDraft(live.draft.id, uuid))?;
+                }
+                if let Some(parent) = reply_to {
+                    self.check_parent(&draft_key.clone().into(), parent)?;
                 }
-                if let Some(draft) = self.current_drafts.remove(&draft_key) {
-                    self.storage
-                        .add_message(
-                            draft.into_message(sender.clone(), current_time),
-                            destination.clone(),
-                        )
-                        .unwrap_or_else(|e| {
-                            warn!("Unable to end draft on message {}: {:?}", uuid, e)
-                        });
+                let Some(mut live) = self.current_drafts.remove(&draft_key) else {
+                    unreachable!("draft was just found");
+                };
+                if reply_to.is_some() {
+                    live.draft.reply_to = reply_to;
+                }
+                self.finish_draft(&sender, &destination, live, content, current_time)?;
+            }
+            Packet::DiscardDraft { uuid } => {
+                let live = self.current_drafts.get(&draft_key)
+                    .filter(|live| live.draft.id == uuid)
+                    .ok_or(ServerError::MissingDraft(draft_key.clone()))?;
+                if live.owner != session {
+                    Err(ServerError::NotDraftOwner(draft_key.clone()))?;
                 }
+                let Some(live) = self.current_drafts.remove(&draft_key) else {
+                    unreachable!("draft was just found");
+                };
+                // only those who could see it hear that it's gone
+                self.discard_draft(&sender, &destination, &live, current_time);
             }
             // all of these just echo
             // Packet::NewMessage { .. } => {}
@@ -312,69 +1193,514 @@ impl<DB: Send + 'static + MessagesDAO> MessageServer<DB> {
                 content,
                 uuid,
                 editing_draft,
+                ..
             } => {
-                if let Some(draft) = self.current_drafts.get_mut(&draft_key) {
-                    if draft.id == uuid {
-                        draft.content = content.clone();
+                // drafts go to whoever may watch them, edits of sent messages to everyone (None)
+                let live = self.current_drafts.get_mut(&draft_key).filter(|live| live.draft.id == uuid);
+                let (revision, watchers) = match live {
+                    Some(live) => {
+                        if live.owner != session {
+                            Err(ServerError::NotDraftOwner(draft_key.clone()))?;
+                        }
+                        let revision = live.draft.set_content(content.clone(), time);
+                        if live.pace(self.edit_interval, current_time) == Pace::Hold {
+                            return Ok(false);
+                        }
+                        let watchers = match live.visibility {
+                            DraftVisibility::Live => recipients.clone(),
+                            DraftVisibility::Typing | DraftVisibility::Hidden => vec![],
+                        };
+                        (revision, Some(watchers))
                     }
-                } else if !editing_draft {
-                    let room_id = draft_key.into();
-                    if let Ok(room) = self.storage.get_room_mut(&room_id) {
-                        room.edit_message(uuid, content.clone())
-                            .unwrap_or_else(|err| {
-                                warn!("Unable to edit message: {:?}", err);
-                            });
+                    None if editing_draft => Err(ServerError::MissingDraft(draft_key.clone()))?,
+                    None => {
+                        let version = self.edit_sent_message(&sender, &draft_key.into(), uuid, content.clone(), current_time)?;
+                        (version, None)
                     }
-                }
-                try_send(SPacket {
-                    sender,
-                    destination,
+                };
+                let edit = Packet::Edit {
+                    content,
+                    uuid,
+                    editing_draft,
+                    revision,
+                };
+                match watchers {
+                    Some(watchers) => {
+                        self.broadcast(&watchers, &sender, &destination, time, &edit)?;
+                    }
+                    // like the message itself, these have to reach everyone eventually
+                    None => self.send_or_enqueue(recipients, SPacket {
+                        sender: sender.clone(),
+                        destination: destination.clone(),
+                        time,
+                        packet: edit.clone(),
+                    })?,
+                }
+                // keep the sender's other connections in sync
+                self.send_except(&sender, Some(session), SPacket {
+                    sender: sender.clone(),
+                    destination: destination.clone(),
                     time,
-                    packet: Packet::Edit {
-                        content,
+                    packet: edit,
+                })?;
+            }
+            Packet::Delta { uuid, revision, op } => {
+                let live = self.current_drafts
+                    .get_mut(&draft_key)
+                    .filter(|live| live.draft.id == uuid)
+                    .ok_or(ServerError::MissingDraft(draft_key.clone()))?;
+                if live.owner != session {
+                    Err(ServerError::NotDraftOwner(draft_key.clone()))?;
+                }
+                if let Err(err) = live.draft.apply(revision, &op, time) {
+                    info!("Rejecting delta on draft {}: {:?}", uuid, err);
+                    // put them back in step with what everyone else sees
+                    let snapshot = Packet::Edit {
+                        uuid,
+                        content: live.draft.content.clone(),
+                        editing_draft: true,
+                        revision: live.draft.revision,
+                    };
+                    self.reply(&sender, session, SPacket {
+                        sender: sender.clone(),
+                        destination: destination.clone(),
+                        time: current_time,
+                        packet: snapshot,
+                    });
+                    return Err(ServerError::BadDelta(uuid, err));
+                }
+                let live_watchers = live.visibility == DraftVisibility::Live;
+                let delta = match live.pace(self.edit_interval, current_time) {
+                    Pace::Relay => Packet::Delta { uuid, revision, op },
+                    Pace::Snapshot => Packet::Edit {
                         uuid,
-                        editing_draft,
+                        content: live.draft.content.clone(),
+                        editing_draft: true,
+                        revision: live.draft.revision,
                     },
+                    Pace::Hold => return Ok(false),
+                };
+                if live_watchers {
+                    self.broadcast(&recipients, &sender, &destination, time, &delta)?;
+                }
+                self.send_except(&sender, Some(session), SPacket {
+                    sender: sender.clone(),
+                    destination: destination.clone(),
+                    time,
+                    packet: delta,
                 })?;
             }
-            packet => {
-                try_send(SPacket {
-                    sender,
+            Packet::SyncRequest { before, limit } => {
+                if limit == 0 {
+                    Err(ServerError::EmptySyncRequest)?;
+                }
+                let limit = limit.min(MAX_SYNC_PAGE);
+                // ask for one extra to find out whether there's more
+                let page = Page {
+                    cursor: before.map_or(Cursor::End, Cursor::Before),
+                    offset: 0,
+                    limit: Some(limit + 1),
+                };
+                let mut messages: Vec<MessageRecord> = match self.storage.get_room(&draft_key.into()) {
+                    Ok(room) => room
+                        .get_messages(&MessageQuery::default(), &page)?
+                        .into_iter()
+                        .map(MessageRecord::from)
+                        .collect(),
+                    // they haven't talked yet
+                    Err(MessageDAOError::MissingRoomId(_)) => vec![],
+                    Err(e) => Err(e)?,
+                };
+                let more = messages.len() > limit;
+                if more {
+                    messages.remove(0);
+                }
+                let (from, reply_destination) = about_conversation(&sender, &destination);
+                self.reply(&sender, session, SPacket {
+                    sender: from,
+                    destination: reply_destination,
+                    time: current_time,
+                    packet: Packet::SyncMessages { messages, more },
+                });
+            }
+            Packet::ReplayRequest { uuid, speed } => {
+                // every replay still playing has its last keystroke waiting
+                let playing = self.scheduled
+                    .values()
+                    .filter(|(_, s, msg)| *s == session && matches!(msg.packet, Packet::Replay { last: true, .. }))
+                    .count();
+                if playing >= MAX_REPLAYS {
+                    Err(ServerError::TooManyReplays(session))?;
+                }
+                let room = self.storage.get_room(&draft_key.into())?;
+                let message = room.get_message(uuid).ok_or(MessageDAOError::MissingMessageId(uuid))?;
+                if message.deleted.is_some() {
+                    Err(MessageDAOError::MessageDeleted(uuid))?;
+                }
+                let mut timeline = room.get_timeline(uuid)?;
+                if timeline.is_empty() {
+                    // nothing was recorded, so the whole thing shows up at once
+                    timeline.push(Keystroke {
+                        time: message.start_time,
+                        op: EditOp::Insert { offset: 0, text: message.content.clone() },
+                    });
+                }
+                let speed = speed.clamp(1, MAX_REPLAY_SPEED) as Timestamp;
+                let first = timeline[0].time;
+                let last = timeline.len() - 1;
+                let (from, reply_destination) = about_conversation(&sender, &destination);
+                for (i, Keystroke { time, op }) in timeline.into_iter().enumerate() {
+                    let at = current_time + time.saturating_sub(first) * 100 / speed;
+                    self.schedule(at, &sender, session, SPacket {
+                        sender: from.clone(),
+                        destination: reply_destination.clone(),
+                        time: current_time,
+                        packet: Packet::Replay { uuid, time, op, last: i == last },
+                    });
+                }
+            }
+            Packet::CreateGroup { members } => {
+                let gc_id = make_group_chat_id();
+                let mut members: Vec<UserId> = members.into_iter().map(make_user_id).collect();
+                members.push(sender.clone());
+                members.sort_by_key(UserId::to_string);
+                members.dedup();
+                info!("{:?} created group {:?} with {:?}", &sender, &gc_id, &members);
+                self.storage.create_group(gc_id.clone(), members)?;
+                self.announce_group(&gc_id, &sender, None, current_time)?;
+            }
+            Packet::AddMember { user } => {
+                let Destination::Group(gc_id) = destination else {
+                    return Err(ServerError::NotAGroup(destination));
+                };
+                self.storage
+                    .get_room_mut(&RoomId::Group(gc_id.clone()))?
+                    .add_member(make_user_id(user))?;
+                self.announce_group(&gc_id, &sender, None, current_time)?;
+            }
+            Packet::LeaveGroup => {
+                let Destination::Group(gc_id) = destination.clone() else {
+                    return Err(ServerError::NotAGroup(destination));
+                };
+                self.storage
+                    .get_room_mut(&RoomId::Group(gc_id.clone()))?
+                    .remove_member(&sender)?;
+                // a half-typed message to a group you left goes nowhere
+                if let Some(live) = self.current_drafts.remove(&draft_key) {
+                    let watchers = self.draft_watchers(&sender, &destination, live.visibility);
+                    self.broadcast(&watchers, &sender, &destination, current_time, &Packet::DiscardDraft {
+                        uuid: live.draft.id,
+                    })?;
+                }
+                self.announce_group(&gc_id, &sender, Some(sender.clone()), current_time)?;
+            }
+            Packet::EditHistoryRequest { uuid } => {
+                let versions = self.storage.get_room(&draft_key.into())?.get_versions(uuid)?;
+                let (from, reply_destination) = about_conversation(&sender, &destination);
+                self.reply(&sender, session, SPacket {
+                    sender: from,
+                    destination: reply_destination,
+                    time: current_time,
+                    packet: Packet::EditHistory { uuid, versions },
+                });
+            }
+            Packet::RepliesRequest { uuid } => {
+                let room = self.storage.get_room(&draft_key.into())?;
+                room.get_message(uuid).ok_or(MessageDAOError::MissingMessageId(uuid))?;
+                let replies = MessageQuery { reply_to: Some(uuid), ..Default::default() };
+                let messages = room
+                    .get_messages(&replies, &Page::default())?
+                    .into_iter()
+                    .map(MessageRecord::from)
+                    .collect();
+                let (from, reply_destination) = about_conversation(&sender, &destination);
+                self.reply(&sender, session, SPacket {
+                    sender: from,
+                    destination: reply_destination,
+                    time: current_time,
+                    packet: Packet::Replies { uuid, messages },
+                });
+            }
+            Packet::MarkRead { uuid } => {
+                self.record_receipt(&draft_key.into(), uuid, &sender, ReceiptStatus::Read, current_time)?;
+            }
+            Packet::ReceiptsRequest { uuid } => {
+                let receipts = self.authored_room(&sender, &draft_key.into(), uuid)?.get_receipts(uuid)?;
+                let (from, reply_destination) = about_conversation(&sender, &destination);
+                self.reply(&sender, session, SPacket {
+                    sender: from,
+                    destination: reply_destination,
+                    time: current_time,
+                    packet: Packet::Receipts { uuid, receipts: receipts.into_iter().map(ReceiptRecord::from).collect() },
+                });
+            }
+            Packet::DeleteMessage { uuid } => {
+                self.authored_room(&sender, &draft_key.into(), uuid)?
+                    .remove_message(uuid, current_time)?;
+                info!("{:?} deleted message {}", &sender, uuid);
+                // whoever hasn't gotten the message yet shouldn't get it at all
+                for recipient in recipients.iter() {
+                    self.storage.purge_backlog(recipient, uuid)?;
+                }
+                let p = SPacket {
+                    sender: sender.clone(),
                     destination,
-                    time,
-                    packet,
+                    time: current_time,
+                    packet: Packet::DeleteMessage { uuid },
+                };
+                // like the message itself, this has to reach them eventually
+                self.send_or_enqueue(recipients, p.clone())?;
+                self.send_except(&sender, Some(session), p)?;
+            }
+            Packet::SetDraftVisibility { visibility, everywhere } => {
+                let conversation = (!everywhere).then_some(&destination);
+                self.storage.set_draft_visibility(&sender, conversation, visibility)?;
+                self.try_send(&sender, SPacket {
+                    sender: sender.clone(),
+                    destination: destination.clone(),
+                    time: current_time,
+                    packet: Packet::SetDraftVisibility { visibility, everywhere },
                 })?;
-            },
+            }
+            // handled above
+            Packet::Heartbeat { .. } => {}
+            Packet::SubscribePresence { users } => {
+                for user in users.into_iter().map(make_user_id) {
+                    self.presence_watchers.entry(user.clone()).or_default().insert(sender.clone());
+                    let presence = self.presence(&user);
+                    self.reply(&sender, session, SPacket {
+                        sender: user,
+                        destination: Destination::User(sender.clone()),
+                        time: current_time,
+                        packet: presence,
+                    });
+                }
+            }
+            Packet::UnsubscribePresence { users } => {
+                for user in users.into_iter().map(make_user_id) {
+                    if let Some(watchers) = self.presence_watchers.get_mut(&user) {
+                        watchers.remove(&sender);
+                        if watchers.is_empty() {
+                            self.presence_watchers.remove(&user);
+                        }
+                    }
+                }
+            }
+            Packet::Ack { seq } => {
+                if let Some(connection) = self.open_senders.get_mut(&sender).and_then(|s| s.get_mut(&session)) {
+                    connection.outbox.ack(seq);
+                }
+            }
+            Packet::SyncMessages { .. }
+            | Packet::GroupInfo { .. }
+            | Packet::Welcome { .. }
+            | Packet::Missed { .. }
+            | Packet::Replay { .. }
+            | Packet::EditHistory { .. }
+            | Packet::Error { .. }
+            | Packet::Accepted { .. }
+            | Packet::Receipt { .. }
+            | Packet::Receipts { .. }
+            | Packet::Replies { .. }
+            | Packet::Presence { .. }
+            // drafts are started with StartDraft, and messages only come out of them, in finish_draft
+            | Packet::NewDraft { .. }
+            | Packet::NewMessage { .. } => Err(ServerError::ServerOnlyPacket(sender))?,
         };
-        if disconnected {
-            // if they disconnect, remove the sending channel
-            self.deregister(&to);
-        }
         Ok(false)
     }
 }
 
+/// Whether a packet is one that's kept for users who aren't connected, rather than only sent live
+fn must_arrive(packet: &Packet) -> bool {
+    matches!(
+        packet,
+        Packet::NewMessage { .. }
+            | Packet::Edit { editing_draft: false, .. }
+            | Packet::DeleteMessage { .. }
+            | Packet::GroupInfo { .. }
+            | Packet::Receipt { .. }
+    )
+}
+
+/// Who an answer about the conversation `sender` has with `destination` should come from, and where to.
+/// Dm answers come "from" the other side of the conversation, so the client knows where they go.
+fn about_conversation(sender: &UserId, destination: &Destination) -> (UserId, Destination) {
+    match destination {
+        Destination::User(other) => (other.clone(), Destination::User(sender.clone())),
+        Destination::Group(_) => (sender.clone(), destination.clone()),
+    }
+}
+
+impl ServerHandle {
+    /// Open a connection for `uid`, see MessageServer::register
+    pub async fn register(
+        &self,
+        uid: UserId,
+        resume: Option<(SessionId, Seq)>,
+    ) -> Result<(SessionId, OutgoingReceiver), ServerError> {
+        let (reply, answer) = oneshot::channel();
+        self.send(Command::Register { uid, resume, reply }).await?;
+        answer.await.map_err(|_| ServerError::Stopped)?
+    }
+
+    pub async fn deregister(&self, uid: UserId, session: SessionId) -> Result<(), ServerError> {
+        self.send(Command::Deregister { uid, session }).await
+    }
+
+    /// Hand the server what arrived on connection `session`
+    pub async fn packet(&self, session: SessionId, incoming: Incoming) -> Result<(), ServerError> {
+        self.send(Command::Packet(session, incoming)).await
+    }
+
+    pub async fn history(
+        &self,
+        room_id: RoomId,
+        query: MessageQuery,
+        page: Page,
+    ) -> Result<Vec<MessageRecord>, ServerError> {
+        let (reply, answer) = oneshot::channel();
+        self.send(Command::History { room_id, query, page, reply }).await?;
+        answer.await.map_err(|_| ServerError::Stopped)?
+    }
+
+    /// Run `f` against account & login token storage on the server task, see auth
+    pub async fn accounts<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut dyn AccountDAO) -> T + Send + 'static,
+    ) -> Result<T, ServerError> {
+        let (reply, answer) = oneshot::channel();
+        self.send(Command::Accounts(Box::new(move |accounts| {
+            let _ = reply.send(f(accounts));
+        })))
+        .await?;
+        answer.await.map_err(|_| ServerError::Stopped)
+    }
+
+    async fn send(&self, command: Command) -> Result<(), ServerError> {
+        self.commands.send(command).await.map_err(|_| ServerError::Stopped)
+    }
+}
+
+impl LiveDraft {
+    /// Whether a change made at `now` goes out right away, with at least `interval` between
+    /// changes sent to watchers. Held back changes are flushed when the interval is up.
+    fn pace(&mut self, interval: Timestamp, now: Timestamp) -> Pace {
+        let next = self.last_broadcast.saturating_add(interval);
+        if now < next {
+            self.flush_at.get_or_insert(next);
+            return Pace::Hold;
+        }
+        self.last_broadcast = now;
+        match self.flush_at.take() {
+            Some(_) => Pace::Snapshot,
+            None => Pace::Relay,
+        }
+    }
+}
+
+impl Outbox {
+    /// Forget everything up to and including `seq`
+    fn ack(&mut self, seq: Seq) {
+        while self.unacked.front().is_some_and(|(s, _)| *s <= seq) {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Whether everything after `acked` is still here to be replayed
+    fn is_complete_after(&self, acked: Seq) -> bool {
+        let oldest = self.unacked.front().map_or(self.next_seq, |(s, _)| *s);
+        oldest <= acked + 1
+    }
+}
+
+impl Connection {
+    /// Numbers the packet and keeps it until it's acknowledged
+    fn send(&mut self, packet: SPacket) -> Result<(), QueueError> {
+        let seq = self.outbox.next_seq;
+        self.tx.send((seq, packet.clone()))?;
+        self.outbox.next_seq += 1;
+        self.outbox.unacked.push_back((seq, packet));
+        if self.outbox.unacked.len() > MAX_UNACKED {
+            // they'll have to sync instead if they ever resume from before this
+            self.outbox.unacked.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl ServerError {
+    /// What to tell the client that caused this
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServerError::MissingDraft(_) => ErrorCode::MissingDraft,
+            ServerError::NotDraftOwner(_) => ErrorCode::NotDraftOwner,
+            ServerError::BadEndDraft(..) => ErrorCode::BadEndDraft,
+            ServerError::ServerOnlyPacket(_) => ErrorCode::ServerOnly,
+            ServerError::NotAMember(..) => ErrorCode::NotAMember,
+            ServerError::NotAGroup(_) => ErrorCode::NotAGroup,
+            ServerError::NotAuthor(..) => ErrorCode::NotAuthor,
+            ServerError::BadDelta(_, DeltaError::StaleRevision(..)) => ErrorCode::StaleRevision,
+            ServerError::BadDelta(_, DeltaError::OutOfBounds) => ErrorCode::OutOfBounds,
+            ServerError::TooManyReplays(_) => ErrorCode::TooManyReplays,
+            ServerError::EmptySyncRequest => ErrorCode::BadPacket,
+            ServerError::DAOError(e) => match e {
+                MessageDAOError::MissingMessageId(_) => ErrorCode::MissingMessage,
+                MessageDAOError::MissingRoomId(_) => ErrorCode::MissingConversation,
+                MessageDAOError::MessageDeleted(_) => ErrorCode::MessageDeleted,
+                MessageDAOError::NotAMember(_) => ErrorCode::NotAMember,
+                MessageDAOError::FixedMembership => ErrorCode::NotAGroup,
+                MessageDAOError::GroupExists(_)
+                | MessageDAOError::UserExists(_)
+                | MessageDAOError::Sqlite(_)
+                | MessageDAOError::Serde(_) => ErrorCode::Internal,
+            },
+            ServerError::NotConnected(..) | ServerError::Stopped => ErrorCode::Internal,
+        }
+    }
+
+    /// The message this was about, if it was about one
+    pub fn message_id(&self) -> Option<MessageId> {
+        match self {
+            ServerError::BadEndDraft(_, received) => Some(*received),
+            ServerError::NotAuthor(_, uuid)
+            | ServerError::BadDelta(uuid, _)
+            | ServerError::DAOError(MessageDAOError::MissingMessageId(uuid) | MessageDAOError::MessageDeleted(uuid)) => {
+                Some(*uuid)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl From<MessageDAOError> for ServerError {
     fn from(value: MessageDAOError) -> Self {
         ServerError::DAOError(value)
     }
 }
 
-pub struct ShutdownHandler(Mutex<Vec<JoinHandle<()>>>);
+/// Stops the server task when Rocket shuts down
+pub struct ShutdownHandler {
+    server: ServerHandle,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
 impl ShutdownHandler {
-    pub fn new(handle: JoinHandle<()>) -> ShutdownHandler {
-        ShutdownHandler(Mutex::new(vec![handle]))
+    pub fn new(server: ServerHandle, task: JoinHandle<()>) -> ShutdownHandler {
+        ShutdownHandler { server, task: Mutex::new(Some(task)) }
     }
 
-    pub fn join(&self) {
-        let mut handles = self.0.lock().unwrap();
-        for thread_handle in handles.drain(..) {
-            match thread_handle.join() {
+    /// Tell the server task to stop and wait for it to wind down, see MessageServer::run
+    pub async fn stop(&self) {
+        if self.server.send(Command::Stop).await.is_err() {
+            warn!("Message server had already stopped");
+        }
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            match task.await {
                 Ok(()) => {}
-                Err(err) => {
-                    panic::resume_unwind(err); // recommended??
-                    // eprintln!("Message server did not unwind successfully!");
-                }
+                Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+                Err(err) => error!("Message server task was cancelled: {:?}", err),
             }
         }
     }
@@ -390,102 +1716,984 @@ impl Fairing for ShutdownHandler {
     }
 
     async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
-        self.join();
+        self.stop().await;
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::identity::make_user_id;
-    use crate::message_server;
-    use crate::packet::{Destination, Packet, SPacket};
+    use crate::identity::{make_session_id, make_user_id, make_user_pair, SessionId, UserId};
+    use crate::message_server::{
+        BacklogLimits, Incoming, MessageServer, ServerError, ShutdownDrafts, DEFAULT_DRAFT_GRACE, MAX_REPLAYS,
+    };
+    use crate::packet::{get_current_time, Destination, ErrorCode, Packet, SPacket};
+    use crate::protocol::{DraftVisibility, EditOp, PresenceStatus, ReceiptStatus};
     use crate::storage::memory_storage::MemoryMessageDatabase;
+    use crate::storage::query::{MessageQuery, Page};
+    use crate::storage::{BacklogDAO, MessageRoomDAO, MessagesDAO, RoomId};
+    use crate::outgoing::{CloseReason, OutgoingReceiver};
     use rocket::futures::StreamExt;
-    use std::sync::{Arc, Mutex};
-    use std::thread;
     use uuid::Uuid;
 
-    // stolen from https://github.com/rust-lang/book/blob/main/packages/trpl/src/lib.rs
-    pub fn run<F: Future>(future: F) -> F::Output {
-        let rt = rocket::tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(future)
+    fn user(name: &str) -> UserId {
+        make_user_id(name.to_string())
+    }
+
+    fn packet(from: &UserId, to: &UserId, packet: Packet) -> SPacket {
+        SPacket {
+            sender: from.clone(),
+            destination: Destination::User(to.clone()),
+            time: 0,
+            packet,
+        }
+    }
+
+    /// Everything the server has sent so far, without waiting for more. Presence comes and goes
+    /// with every connection, so it's left out (see test_presence)
+    fn drain_packets(rx: &mut OutgoingReceiver) -> Vec<SPacket> {
+        let mut packets = vec![];
+        while let Some((_, p)) = rx.try_recv() {
+            if !matches!(p.packet, Packet::Presence { .. }) {
+                packets.push(p);
+            }
+        }
+        packets
+    }
+
+    fn drain(rx: &mut OutgoingReceiver) -> Vec<Packet> {
+        drain_packets(rx).into_iter().map(|p| p.packet).collect()
+    }
+
+    /// Opens a new connection for `uid` and takes its Welcome
+    fn connect(
+        server: &mut MessageServer<MemoryMessageDatabase>,
+        uid: &UserId,
+    ) -> (SessionId, OutgoingReceiver) {
+        let (session, mut rx) = server.register(uid.clone(), None).unwrap();
+        match rx.try_recv() {
+            Some((_, SPacket { packet: Packet::Welcome { resumed: false, .. }, .. })) => {}
+            other => panic!("expected a Welcome first, got {:?}", other),
+        }
+        (session, rx)
+    }
+
+    /// Has `from` type and send `content` to `to`, returning the new message's id
+    fn send_message(
+        server: &mut MessageServer<MemoryMessageDatabase>,
+        from: &UserId,
+        to: &UserId,
+        content: &str,
+    ) -> Uuid {
+        let (session, mut from_rx) = connect(server, from);
+        server.process_message(session, packet(from, to, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = drain(&mut from_rx)
+            .into_iter()
+            .find_map(|p| match p {
+                Packet::NewDraft { uuid, .. } => Some(uuid),
+                _ => None,
+            })
+            .unwrap();
+        server
+            .process_message(session, packet(from, to, Packet::EndDraft { uuid, content: Some(content.to_string()), reply_to: None }))
+            .unwrap();
+        server.deregister(from, session);
+        uuid
     }
 
     #[test]
-    fn test_server_start() {
-        // usually the message server runs on a separate thread,
-        // but for this example it was easier to get it set up on the main
-        // thread... hopefully this problem is not in the main server
-
-        let server = message_server::MessageServer::new(MemoryMessageDatabase::new());
-        let server = Arc::new(Mutex::new(server));
-        let (s_sender, s_receiver) = std::sync::mpsc::channel();
-        let uid_a = make_user_id("A".to_string());
-        let uid_b = make_user_id("B".to_string());
-
-        let inner_server = Arc::clone(&server);
-        let inner_uid_a = uid_a.clone();
-        let inner_uid_b = uid_b.clone();
-        let handle = thread::spawn(move || {
-            s_sender
-                .send(SPacket {
-                    sender: inner_uid_a.clone(),
-                    destination: Destination::User(inner_uid_b.clone()),
-                    time: 0,
-                    packet: Packet::NewMessage {
-                        uuid: Uuid::new_v4(),
-                        content: "howdy".to_string(),
-                        start_time: 0,
-                        end_time: 0,
-                    },
+    fn test_history_sync() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let ids: Vec<Uuid> = [(&a, &b, "one"), (&b, &a, "two"), (&a, &b, "three")]
+            .into_iter()
+            .map(|(from, to, content)| send_message(&mut server, from, to, content))
+            .collect();
+        let (_, mut rx_b) = connect(&mut server, &b);
+        drain(&mut rx_b);
+
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        // receipts that came in while they were away
+        drain(&mut rx_a);
+        server
+            .process_message(session_a, packet(&a, &b, Packet::SyncRequest { before: None, limit: 2 }))
+            .unwrap();
+        match drain(&mut rx_a).as_slice() {
+            [Packet::SyncMessages { messages, more: true }] => {
+                let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+                assert_eq!(contents, vec!["two", "three"]);
+            }
+            other => panic!("expected a page of history, got {:?}", other),
+        }
+
+        server
+            .process_message(session_a, packet(&a, &b, Packet::SyncRequest { before: Some(ids[1]), limit: 2 }))
+            .unwrap();
+        match drain(&mut rx_a).as_slice() {
+            [Packet::SyncMessages { messages, more: false }] => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].uuid, ids[0]);
+                assert!(messages[0].start_time <= messages[0].end_time);
+            }
+            other => panic!("expected the rest of the history, got {:?}", other),
+        }
+        // an empty page would say there's more forever
+        server.handle(session_a, Incoming::Packet(packet(&a, &b, Packet::SyncRequest { before: None, limit: 0 }), Some(1)));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error { code: ErrorCode::BadPacket, uuid: None, request_id: Some(1) }]);
+        // nothing from the request leaks to the other side
+        assert!(drain(&mut rx_b).is_empty());
+    }
+
+    #[test]
+    fn test_group_chat() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b, c, d) = (user("A"), user("B"), user("C"), user("D"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        server
+            .process_message(session_a, packet(&a, &a, Packet::CreateGroup { members: vec!["B".to_string(), "C".to_string()] }))
+            .unwrap();
+        let group = drain(&mut rx_b);
+        let gc_id = match drain_packets(&mut rx_a).as_slice() {
+            [SPacket { destination: Destination::Group(gc_id), packet, .. }] => {
+                assert_eq!(group, vec![packet.clone()]);
+                assert_eq!(packet, &Packet::GroupInfo {
+                    members: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+                });
+                gc_id.clone()
+            }
+            other => panic!("expected A to hear about the group, got {:?}", other),
+        };
+        let to_group = |from: &UserId, packet: Packet| SPacket {
+            sender: from.clone(),
+            destination: Destination::Group(gc_id.clone()),
+            time: 0,
+            packet,
+        };
+
+        server.process_message(session_a, to_group(&a, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_b).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected B to see the draft, got {:?}", other),
+        };
+        server
+            .process_message(session_a, to_group(&a, Packet::EndDraft { uuid, content: Some("hi all".to_string()), reply_to: None }))
+            .unwrap();
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::EndDraft { .. }]));
+
+        // C was away, so they get the whole thing when they show up
+        let (_, mut rx_c) = connect(&mut server, &c);
+        let caught_up = drain(&mut rx_c);
+        assert!(matches!(caught_up.as_slice(), [
+            Packet::GroupInfo { .. },
+            Packet::NewMessage { content, .. },
+        ] if content == "hi all"));
+
+        // outsiders can't talk in the group
+        assert!(server.process_message(make_session_id(), to_group(&d, Packet::StartDraft { reply_to: None })).is_err());
+
+        server.process_message(session_b, to_group(&b, Packet::LeaveGroup)).unwrap();
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::GroupInfo { members }] if members.len() == 2));
+        assert!(server.process_message(session_b, to_group(&b, Packet::StartDraft { reply_to: None })).is_err());
+
+        // a message that couldn't be kept is an error, and nobody hears it was sent
+        server.process_message(session_a, to_group(&a, Packet::StartDraft { reply_to: None })).unwrap();
+        drain(&mut rx_a);
+        drain(&mut rx_c);
+        let group = Destination::Group(gc_id.clone());
+        server.storage.get_room_mut(&RoomId::Group(gc_id.clone())).unwrap().remove_member(&a).unwrap();
+        let live = server.current_drafts.remove(&(a.clone(), group.clone())).unwrap();
+        assert!(server.finish_draft(&a, &group, live, Some("bye".to_string()), 0).is_err());
+        assert!(drain(&mut rx_a).is_empty());
+        assert!(drain(&mut rx_c).is_empty());
+    }
+
+    #[test]
+    fn test_multiple_devices() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (phone, mut rx_phone) = connect(&mut server, &a);
+        let (laptop, mut rx_laptop) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+
+        // both of A's devices hear about the draft, only the phone may type in it
+        server.process_message(phone, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_laptop).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected the laptop to see the draft, got {:?}", other),
+        };
+        assert_eq!(drain(&mut rx_phone), drain(&mut rx_b));
+        let edit = Packet::Edit { uuid, content: "hel".to_string(), editing_draft: true, revision: 0 };
+        assert!(server.process_message(laptop, packet(&a, &b, edit.clone())).is_err());
+        server.process_message(phone, packet(&a, &b, edit)).unwrap();
+        let relayed = Packet::Edit { uuid, content: "hel".to_string(), editing_draft: true, revision: 1 };
+        assert_eq!(drain(&mut rx_laptop), vec![relayed.clone()]);
+        assert_eq!(drain(&mut rx_b), vec![relayed]);
+        assert!(drain(&mut rx_phone).is_empty());
+
+        // a third device is caught up on the draft in progress
+        let (tablet, mut rx_tablet) = connect(&mut server, &a);
+        assert!(matches!(drain(&mut rx_tablet).as_slice(), [
+            Packet::NewDraft { .. },
+            Packet::Edit { content, .. },
+        ] if content == "hel"));
+
+        // and only the phone may throw it away
+        let discard = Packet::DiscardDraft { uuid };
+        assert!(matches!(
+            server.process_message(laptop, packet(&a, &b, discard)),
+            Err(ServerError::NotDraftOwner(_))
+        ));
+
+        // closing another device leaves the draft alone
+        server.deregister(&a, laptop);
+        server.deregister(&a, tablet);
+        assert!(drain(&mut rx_b).is_empty());
+        server
+            .process_message(phone, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None }))
+            .unwrap();
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::EndDraft { .. }]));
+
+        // closing the phone throws away drafts typed on it, once nobody comes back for them
+        server.process_message(phone, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        drain(&mut rx_b);
+        server.deregister(&a, phone);
+        assert!(drain(&mut rx_b).is_empty());
+        let expires = server.next_due().unwrap();
+        server.run_due(expires - 1);
+        assert!(drain(&mut rx_b).is_empty());
+        server.run_due(expires);
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::DiscardDraft { .. }]));
+        assert_eq!(server.next_due(), None);
+    }
+
+    #[test]
+    fn test_draft_survives_reconnect() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (phone, mut rx_phone) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+        server.process_message(phone, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_phone).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected A's draft, got {:?}", other),
+        };
+        let typed = Packet::Delta { uuid, revision: 0, op: EditOp::Insert { offset: 0, text: "half a".to_string() } };
+        server.process_message(phone, packet(&a, &b, typed)).unwrap();
+        drain(&mut rx_b);
+
+        // the connection blips; B doesn't hear anything about it
+        server.deregister(&a, phone);
+        assert!(drain(&mut rx_b).is_empty());
+        // and whatever the dead socket still had in flight is turned away
+        let late = Packet::Delta { uuid, revision: 1, op: EditOp::Insert { offset: 6, text: "!".to_string() } };
+        assert!(matches!(server.process_message(phone, packet(&a, &b, late)), Err(ServerError::NotConnected(..))));
+        assert!(drain(&mut rx_b).is_empty());
+
+        // a fresh connection is handed the draft back and can finish it
+        let (again, mut rx_again) = connect(&mut server, &a);
+        assert!(matches!(drain(&mut rx_again).as_slice(), [
+            Packet::NewDraft { .. },
+            Packet::Edit { content, revision: 1, .. },
+        ] if content == "half a"));
+        assert_eq!(server.next_due(), None);
+        let more = Packet::Delta { uuid, revision: 1, op: EditOp::Insert { offset: 6, text: " thought".to_string() } };
+        server.process_message(again, packet(&a, &b, more)).unwrap();
+        server.process_message(again, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None })).unwrap();
+        let stored = server.history(&RoomId::DM(make_user_pair(a, b)), &MessageQuery::default(), &Page::default());
+        assert_eq!(stored.unwrap()[0].content, "half a thought");
+    }
+
+    #[test]
+    fn test_delta_edits() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_a).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected A's draft, got {:?}", other),
+        };
+        drain(&mut rx_b);
+
+        let delta = |revision, op| Packet::Delta { uuid, revision, op };
+        let typed = [
+            delta(0, EditOp::Insert { offset: 0, text: "helo".to_string() }),
+            delta(1, EditOp::Insert { offset: 3, text: "l".to_string() }),
+        ];
+        for d in typed.iter() {
+            server.process_message(session_a, packet(&a, &b, d.clone())).unwrap();
+        }
+        // watchers get exactly what was typed, the sender hears nothing back
+        assert_eq!(drain(&mut rx_b), typed.to_vec());
+        assert!(drain(&mut rx_a).is_empty());
+
+        // a delta against an old revision is refused, and gets the sender a snapshot to continue from
+        let snapshot = Packet::Edit { uuid, content: "hello".to_string(), editing_draft: true, revision: 2 };
+        let stale = delta(1, EditOp::Delete { offset: 0, len: 1 });
+        server.handle(session_a, Incoming::Packet(packet(&a, &b, stale), Some(7)));
+        assert!(drain(&mut rx_b).is_empty());
+        assert_eq!(drain(&mut rx_a), vec![
+            snapshot.clone(),
+            Packet::Error { code: ErrorCode::StaleRevision, uuid: Some(uuid), request_id: Some(7) },
+        ]);
+        let past_the_end = delta(2, EditOp::Insert { offset: 6, text: "!".to_string() });
+        server.handle(session_a, Incoming::Packet(packet(&a, &b, past_the_end), Some(8)));
+        assert!(drain(&mut rx_b).is_empty());
+        assert_eq!(drain(&mut rx_a), vec![
+            snapshot,
+            Packet::Error { code: ErrorCode::OutOfBounds, uuid: Some(uuid), request_id: Some(8) },
+        ]);
+
+        server.process_message(session_a, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None })).unwrap();
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::EndDraft { .. }]));
+        let stored = server.history(&RoomId::DM(make_user_pair(a, b)), &MessageQuery::default(), &Page::default());
+        assert_eq!(stored.unwrap()[0].content, "hello");
+    }
+
+    #[test]
+    fn test_throttled_edits() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        // an hour, so nothing goes out early because the test was slow
+        server.edit_interval = 60 * 60 * 1_000_000;
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_a).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected A's draft, got {:?}", other),
+        };
+        drain(&mut rx_b);
+        let edit = |content: &str| Packet::Edit { uuid, content: content.to_string(), editing_draft: true, revision: 0 };
+
+        // the first change goes right out, the rest wait their turn
+        server.process_message(session_a, packet(&a, &b, edit("h"))).unwrap();
+        assert_eq!(drain(&mut rx_b).len(), 1);
+        let typed = Packet::Delta { uuid, revision: 1, op: EditOp::Insert { offset: 1, text: "i".to_string() } };
+        server.process_message(session_a, packet(&a, &b, typed)).unwrap();
+        server.process_message(session_a, packet(&a, &b, edit("hi!"))).unwrap();
+        assert!(drain(&mut rx_b).is_empty());
+
+        // only the newest content is flushed, once it's due
+        let due = server.next_due().unwrap();
+        server.run_due(due - 1);
+        assert!(drain(&mut rx_b).is_empty());
+        server.run_due(due);
+        assert_eq!(drain(&mut rx_b), vec![Packet::Edit {
+            uuid,
+            content: "hi!".to_string(),
+            editing_draft: true,
+            revision: 3,
+        }]);
+
+        // ending the draft doesn't wait for the next flush
+        server.process_message(session_a, packet(&a, &b, edit("hi!!"))).unwrap();
+        server.process_message(session_a, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None })).unwrap();
+        assert_eq!(drain(&mut rx_b), vec![Packet::EndDraft { uuid, content: Some("hi!!".to_string()), reply_to: None }]);
+        assert_eq!(server.next_due(), None);
+        assert!(drain(&mut rx_a).iter().all(|p| matches!(p, Packet::EndDraft { .. } | Packet::Receipt { .. })));
+    }
+
+    #[test]
+    fn test_edit_history() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let uuid = send_message(&mut server, &a, &b, "helo");
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        drain(&mut rx_b);
+        drain(&mut rx_a);
+        let edit = |content: &str| Packet::Edit { uuid, content: content.to_string(), editing_draft: false, revision: 0 };
+        for content in ["hello", "hello!"] {
+            server.process_message(session_a, packet(&a, &b, edit(content))).unwrap();
+        }
+        let versions: Vec<_> = drain(&mut rx_b)
+            .into_iter()
+            .map(|p| match p {
+                Packet::Edit { revision, .. } => revision,
+                other => panic!("expected an edit, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(versions, vec![1, 2]);
+
+        // history says it was edited, and either side can see what it said before
+        let stored = server
+            .history(&RoomId::DM(make_user_pair(a.clone(), b.clone())), &MessageQuery::default(), &Page::default())
+            .unwrap();
+        assert!(stored[0].edited.is_some());
+        server.process_message(session_b, packet(&b, &a, Packet::EditHistoryRequest { uuid })).unwrap();
+        match drain_packets(&mut rx_b).as_slice() {
+            [SPacket { sender, packet: Packet::EditHistory { versions, .. }, .. }] => {
+                assert_eq!(sender, &a);
+                let contents: Vec<&str> = versions.iter().map(|v| v.content.as_str()).collect();
+                assert_eq!(contents, vec!["helo", "hello", "hello!"]);
+                assert!(versions.windows(2).all(|w| w[0].time <= w[1].time));
+            }
+            other => panic!("expected the edit history, got {:?}", other),
+        }
+        assert!(drain(&mut rx_a).is_empty());
+    }
+
+    #[test]
+    fn test_edit_authorization() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let uuid = send_message(&mut server, &a, &b, "mine");
+        let (session_a, _rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        drain(&mut rx_b);
+        let edit = |uuid, content: &str| Packet::Edit { uuid, content: content.to_string(), editing_draft: false, revision: 0 };
+
+        // B can't put words in A's mouth, nor edit what doesn't exist
+        assert!(server.process_message(session_b, packet(&b, &a, edit(uuid, "yours"))).is_err());
+        let missing = Uuid::new_v4();
+        assert!(server.process_message(session_a, packet(&a, &b, edit(missing, "?"))).is_err());
+        assert!(drain(&mut rx_b).is_empty());
+        let stored = server.history(&RoomId::DM(make_user_pair(a.clone(), b.clone())), &MessageQuery::default(), &Page::default());
+        assert_eq!(stored.unwrap()[0].content, "mine");
+
+        // an edit made while B is away waits for them
+        server.deregister(&b, session_b);
+        let recent = SPacket { time: get_current_time(), ..packet(&a, &b, edit(uuid, "all mine")) };
+        server.process_message(session_a, recent).unwrap();
+        let (_, mut rx_b) = connect(&mut server, &b);
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::Edit { content, revision: 1, .. }] if content == "all mine"));
+    }
+
+    #[test]
+    fn test_request_outcomes() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+        let request = |packet, request_id| Incoming::Packet(packet, request_id);
+
+        // the client hears back about requests it numbered, and about every failure
+        server.handle(session_a, request(packet(&a, &b, Packet::StartDraft { reply_to: None }), Some(1)));
+        let uuid = match drain(&mut rx_a).as_slice() {
+            [Packet::NewDraft { uuid, .. }, Packet::Accepted { request_id: 1 }] => *uuid,
+            other => panic!("expected the draft and an Accepted, got {:?}", other),
+        };
+        let wrong = Uuid::new_v4();
+        server.handle(session_a, request(packet(&a, &b, Packet::EndDraft { uuid: wrong, content: None, reply_to: None }), Some(2)));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error {
+            code: ErrorCode::BadEndDraft,
+            uuid: Some(wrong),
+            request_id: Some(2),
+        }]);
+        server.handle(session_a, request(packet(&a, &a, Packet::Accepted { request_id: 3 }), None));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error { code: ErrorCode::ServerOnly, uuid: None, request_id: None }]);
+        let forged = Packet::NewMessage { uuid, content: "hi".to_string(), start_time: 0, end_time: 0, reply_to: None };
+        server.handle(session_a, request(packet(&a, &b, forged), None));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error { code: ErrorCode::ServerOnly, uuid: None, request_id: None }]);
+        assert!(drain(&mut rx_b).iter().all(|p| !matches!(p, Packet::NewMessage { .. })));
+        let forged = Packet::NewDraft { uuid: wrong, start_time: 0, reply_to: None };
+        server.handle(session_a, request(packet(&a, &b, forged), None));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error { code: ErrorCode::ServerOnly, uuid: None, request_id: None }]);
+        assert!(drain(&mut rx_b).is_empty());
+        let edit = Packet::Edit { uuid, content: "hi".to_string(), editing_draft: false, revision: 0 };
+        server.handle(session_a, request(packet(&a, &b, edit), None));
+        drain(&mut rx_b);
+        server.handle(session_a, request(packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None }), None));
+        assert!(drain(&mut rx_a).iter().all(|p| !matches!(p, Packet::Error { .. } | Packet::Accepted { .. })));
+
+        // someone else's message
+        server.handle(session_a, request(packet(&a, &b, Packet::DeleteMessage { uuid: wrong }), Some(4)));
+        assert!(matches!(drain(&mut rx_a).as_slice(), [Packet::Error { code: ErrorCode::MissingMessage, .. }]));
+
+        // packets that can't be read are answered too, with whatever id could be found
+        let unreadable = crate::packet::WebPacket::try_from(rocket_ws::Message::Text(
+            r#"{"content": "Nonsense", "request_id": 5}"#.to_string(),
+        ))
+        .unwrap_err();
+        server.handle(session_a, Incoming::Unreadable(a.clone(), unreadable));
+        assert_eq!(drain(&mut rx_a), vec![Packet::Error { code: ErrorCode::BadPacket, uuid: None, request_id: Some(5) }]);
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let uuid = send_message(&mut server, &a, &b, "oops");
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        server.process_message(session_a, packet(&a, &b, Packet::DeleteMessage { uuid })).unwrap();
+        assert!(drain(&mut rx_a).is_empty());
+        assert!(server.process_message(session_a, packet(&a, &b, Packet::DeleteMessage { uuid })).is_err());
+
+        // B was away, so they never see what it said, only that it was deleted
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        assert_eq!(drain(&mut rx_b), vec![Packet::DeleteMessage { uuid }]);
+
+        // history keeps its place, without the content
+        let stored = server
+            .history(&RoomId::DM(make_user_pair(a.clone(), b.clone())), &MessageQuery::default(), &Page::default())
+            .unwrap();
+        assert_eq!((stored[0].uuid, stored[0].content.as_str()), (uuid, ""));
+        assert!(stored[0].deleted.is_some());
+
+        // only the sender can take a message back
+        let mine = send_message(&mut server, &a, &b, "mine");
+        drain(&mut rx_a);
+        drain(&mut rx_b);
+        assert!(server.process_message(session_b, packet(&b, &a, Packet::DeleteMessage { uuid: mine })).is_err());
+        assert!(drain(&mut rx_a).is_empty());
+    }
+
+    #[test]
+    fn test_receipts() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let receipt = |p: &SPacket| match p.packet {
+            Packet::Receipt { status, .. } => (p.sender.clone(), status),
+            ref other => panic!("expected a receipt, got {:?}", other),
+        };
+
+        // B was away, so A hears it was delivered once B picks it up from the backlog
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let waiting = send_message(&mut server, &a, &b, "are you there?");
+        drain(&mut rx_a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        drain(&mut rx_b);
+        assert_eq!(drain_packets(&mut rx_a).iter().map(receipt).collect::<Vec<_>>(), vec![
+            (b.clone(), ReceiptStatus::Delivered)
+        ]);
+
+        // and right away while B is connected
+        let live = send_message(&mut server, &a, &b, "hello");
+        let sent = drain_packets(&mut rx_a);
+        assert_eq!(receipt(sent.last().unwrap()), (b.clone(), ReceiptStatus::Delivered));
+        assert!(matches!(drain(&mut rx_b).last(), Some(Packet::EndDraft { .. })));
+
+        // reading is reported once
+        for _ in 0..2 {
+            server.process_message(session_b, packet(&b, &a, Packet::MarkRead { uuid: live })).unwrap();
+        }
+        assert_eq!(drain_packets(&mut rx_a).iter().map(receipt).collect::<Vec<_>>(), vec![
+            (b.clone(), ReceiptStatus::Read)
+        ]);
+
+        // only the sender gets to ask where their message got to
+        server.process_message(session_a, packet(&a, &b, Packet::ReceiptsRequest { uuid: live })).unwrap();
+        match drain(&mut rx_a).as_slice() {
+            [Packet::Receipts { receipts, .. }] => {
+                assert_eq!(receipts.len(), 1);
+                assert_eq!((receipts[0].user.as_str(), receipts[0].status), ("B", ReceiptStatus::Read));
+            }
+            other => panic!("expected the receipts, got {:?}", other),
+        }
+        assert!(server.process_message(session_b, packet(&b, &a, Packet::ReceiptsRequest { uuid: waiting })).is_err());
+        // marking your own message read does nothing
+        server.process_message(session_a, packet(&a, &b, Packet::MarkRead { uuid: waiting })).unwrap();
+        assert!(drain(&mut rx_a).is_empty());
+        assert!(drain(&mut rx_b).is_empty());
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b, c) = (user("A"), user("B"), user("C"));
+        // (whose, status, whether it says when they were last seen)
+        let presence = |rx: &mut OutgoingReceiver| {
+            std::iter::from_fn(|| rx.try_recv())
+                .filter_map(|(_, p)| match p.packet {
+                    Packet::Presence { status, last_seen } => Some((p.sender.to_string(), status, last_seen.is_some())),
+                    _ => None,
                 })
-                .unwrap();
+                .collect::<Vec<_>>()
+        };
+        send_message(&mut server, &a, &b, "hi");
+
+        // C doesn't talk to A, but can still subscribe
+        let (session_c, mut rx_c) = connect(&mut server, &c);
+        server.process_message(session_c, packet(&c, &c, Packet::SubscribePresence { users: vec!["A".to_string()] })).unwrap();
+        assert_eq!(presence(&mut rx_c), vec![("A".to_string(), PresenceStatus::Offline, true)]);
 
-            println!("packet sent");
-            let mut rx_b = inner_server
-                .lock()
+        let (_, mut rx_b) = connect(&mut server, &b);
+        let (session_a, _rx_a) = connect(&mut server, &a);
+        let online = vec![("A".to_string(), PresenceStatus::Online, false)];
+        assert_eq!(presence(&mut rx_b), online);
+        assert_eq!(presence(&mut rx_c), online);
+
+        // going idle is only news once, and anything else they send brings them back
+        for _ in 0..2 {
+            server.process_message(session_a, packet(&a, &a, Packet::Heartbeat { idle: true })).unwrap();
+        }
+        assert_eq!(presence(&mut rx_b), vec![("A".to_string(), PresenceStatus::Idle, true)]);
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        assert_eq!(presence(&mut rx_b), online);
+
+        assert_eq!(presence(&mut rx_c).len(), 2);
+
+        // unsubscribing leaves only the people they talk to
+        server.process_message(session_c, packet(&c, &c, Packet::UnsubscribePresence { users: vec!["A".to_string()] })).unwrap();
+        server.deregister(&a, session_a);
+        assert_eq!(presence(&mut rx_b), vec![("A".to_string(), PresenceStatus::Offline, true)]);
+        assert!(presence(&mut rx_c).iter().all(|(who, ..)| who != "A"));
+        assert!(server.process_message(session_c, packet(&c, &c, Packet::Presence {
+            status: PresenceStatus::Online,
+            last_seen: None,
+        })).is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b, c) = (user("A"), user("B"), user("C"));
+        let question = send_message(&mut server, &a, &b, "question?");
+        let elsewhere = send_message(&mut server, &c, &a, "unrelated");
+        let (_, mut rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        let new_draft = |rx: &mut OutgoingReceiver| {
+            drain(rx)
+                .into_iter()
+                .find_map(|p| match p {
+                    Packet::NewDraft { uuid, reply_to, .. } => Some((uuid, reply_to)),
+                    _ => None,
+                })
                 .unwrap()
-                .register(inner_uid_b.clone())
+        };
+
+        // only messages of the same conversation can be replied to
+        let start = |reply_to| packet(&b, &a, Packet::StartDraft { reply_to: Some(reply_to) });
+        let refused = server.process_message(session_b, start(elsewhere)).unwrap_err();
+        assert_eq!(refused.code(), ErrorCode::MissingMessage);
+        server.process_message(session_b, start(question)).unwrap();
+        let (uuid, reply_to) = new_draft(&mut rx_b);
+        assert_eq!(new_draft(&mut rx_a), (uuid, Some(question)));
+        assert_eq!(reply_to, Some(question));
+        let end = Packet::EndDraft { uuid, content: Some("answer".to_string()), reply_to: None };
+        server.process_message(session_b, packet(&b, &a, end)).unwrap();
+        assert!(matches!(drain(&mut rx_a).as_slice(), [Packet::EndDraft { reply_to: Some(parent), .. }] if *parent == question));
+
+        // or it can be decided when it's sent
+        server.process_message(session_b, packet(&b, &a, Packet::StartDraft { reply_to: None })).unwrap();
+        let (uuid, _) = new_draft(&mut rx_b);
+        let end = |reply_to| Packet::EndDraft { uuid, content: Some("also".to_string()), reply_to: Some(reply_to) };
+        assert!(server.process_message(session_b, packet(&b, &a, end(Uuid::new_v4()))).is_err());
+        server.process_message(session_b, packet(&b, &a, end(question))).unwrap();
+
+        server.process_message(session_b, packet(&b, &a, Packet::RepliesRequest { uuid: question })).unwrap();
+        match drain(&mut rx_b).last() {
+            Some(Packet::Replies { uuid, messages }) => {
+                assert_eq!(*uuid, question);
+                let replies: Vec<(&str, _)> = messages.iter().map(|m| (m.content.as_str(), m.reply_to)).collect();
+                assert_eq!(replies, vec![("answer", Some(question)), ("also", Some(question))]);
+            }
+            other => panic!("expected the replies, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_private_drafts() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        let set = |visibility, everywhere| Packet::SetDraftVisibility { visibility, everywhere };
+        let mut type_message = |server: &mut MessageServer<MemoryMessageDatabase>, content: &str| {
+            server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+            let uuid = drain(&mut rx_a)
+                .into_iter()
+                .find_map(|p| match p {
+                    Packet::NewDraft { uuid, .. } => Some(uuid),
+                    _ => None,
+                })
                 .unwrap();
-            run(async move {
-                let packet = rx_b.next().await.unwrap();
-                match &packet.packet {
-                    Packet::NewMessage { content: msg, .. } => {
-                        println!("message from {:?}: \"{}\"", packet.sender, msg);
-                        msg.clone()
-                    }
-                    _ => panic!("crashout"),
-                }
-            })
-        });
+            // one keystroke at a time
+            for (revision, c) in content.chars().enumerate() {
+                let op = EditOp::Insert { offset: revision, text: c.to_string() };
+                let delta = Packet::Delta { uuid, revision: revision as u64, op };
+                server.process_message(session_a, packet(&a, &b, delta)).unwrap();
+            }
+            server.process_message(session_a, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None })).unwrap();
+            uuid
+        };
 
-        for spacket in s_receiver {
-            println!("Acquiring server lock...");
-            let mut s = server.lock().unwrap();
-            println!("Got lock.");
-            match s.process_message(spacket) {
-                Ok(sent) => {
-                    if sent {
-                        println!("Message routed & sent!")
-                    } else {
-                        println!("Message added to backlog.")
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error occurred while sending message: {e:?}")
-                }
-            };
-            println!("Releasing lock...");
+        // hidden from B: nothing until it's sent, then the whole message
+        server.process_message(session_a, packet(&a, &b, set(Some(DraftVisibility::Hidden), false))).unwrap();
+        let secret = type_message(&mut server, "secret");
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::NewMessage { content, .. }] if content == "secret"));
+
+        // and a replay of it shows up all at once too
+        server.process_message(session_b, packet(&b, &a, Packet::ReplayRequest { uuid: secret, speed: 100 })).unwrap();
+        while let Some(at) = server.next_due() {
+            server.run_due(at);
+        }
+        assert!(matches!(drain(&mut rx_b).as_slice(), [
+            Packet::Replay { op: EditOp::Insert { offset: 0, text }, last: true, .. },
+        ] if text == "secret"));
+
+        // typing indicator everywhere, once the dm's own choice is gone
+        server.process_message(session_a, packet(&a, &b, set(Some(DraftVisibility::Typing), true))).unwrap();
+        server.process_message(session_a, packet(&a, &b, set(None, false))).unwrap();
+        type_message(&mut server, "shh");
+        assert!(matches!(drain(&mut rx_b).as_slice(), [
+            Packet::NewDraft { .. },
+            Packet::EndDraft { content: Some(content), .. },
+        ] if content == "shh"));
+
+        // back to live
+        server.process_message(session_a, packet(&a, &b, set(None, true))).unwrap();
+        type_message(&mut server, "hi");
+        assert!(matches!(drain(&mut rx_b).as_slice(), [
+            Packet::NewDraft { .. },
+            Packet::Delta { .. },
+            Packet::Delta { .. },
+            Packet::EndDraft { content: None, .. },
+        ]));
+
+        // throwing away a hidden draft is as quiet as typing it
+        server.process_message(session_a, packet(&a, &b, set(Some(DraftVisibility::Hidden), false))).unwrap();
+        drain(&mut rx_a);
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_a).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected A's draft, got {:?}", other),
+        };
+        server.process_message(session_a, packet(&a, &b, Packet::DiscardDraft { uuid })).unwrap();
+        assert_eq!(drain(&mut rx_a), vec![Packet::DiscardDraft { uuid }]);
+        assert!(drain(&mut rx_b).is_empty());
+        assert!(matches!(
+            server.process_message(session_a, packet(&a, &b, Packet::DiscardDraft { uuid })),
+            Err(ServerError::MissingDraft(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        let uuid = match drain(&mut rx_a).as_slice() {
+            [Packet::NewDraft { uuid, .. }] => *uuid,
+            other => panic!("expected A's draft, got {:?}", other),
+        };
+        let typed = [
+            (1_000, EditOp::Insert { offset: 0, text: "hi".to_string() }),
+            (3_000, EditOp::Insert { offset: 2, text: "!".to_string() }),
+        ];
+        for (revision, (time, op)) in typed.iter().enumerate() {
+            let delta = Packet::Delta { uuid, revision: revision as u64, op: op.clone() };
+            server.process_message(session_a, SPacket { time: *time, ..packet(&a, &b, delta) }).unwrap();
+        }
+        server.process_message(session_a, packet(&a, &b, Packet::EndDraft { uuid, content: None, reply_to: None })).unwrap();
+
+        // B watches it at double speed
+        let (session_b, mut rx_b) = connect(&mut server, &b);
+        drain(&mut rx_b);
+        server
+            .process_message(session_b, packet(&b, &a, Packet::ReplayRequest { uuid, speed: 200 }))
+            .unwrap();
+        assert!(drain(&mut rx_b).is_empty());
+        let start = server.next_due().unwrap();
+        let due: Vec<u64> = server.scheduled.keys().map(|(at, _)| at - start).collect();
+        assert_eq!(due, vec![0, 1_000]);
+        server.run_due(start);
+        assert_eq!(drain(&mut rx_b), vec![Packet::Replay { uuid, time: 1_000, op: typed[0].1.clone(), last: false }]);
+        server.run_due(start + 1_000);
+        assert_eq!(drain(&mut rx_b), vec![Packet::Replay { uuid, time: 3_000, op: typed[1].1.clone(), last: true }]);
+        assert_eq!(server.next_due(), None);
+
+        // only people in the conversation can watch
+        let (session_c, _rx_c) = connect(&mut server, &user("C"));
+        let snoop = packet(&user("C"), &a, Packet::ReplayRequest { uuid, speed: 100 });
+        assert!(server.process_message(session_c, snoop).is_err());
+        assert!(server.process_message(session_b, packet(&b, &b, Packet::Replay {
+            uuid,
+            time: 0,
+            op: typed[0].1.clone(),
+            last: true,
+        })).is_err());
+
+        // a connection can only have so many replays playing, and they stop when it goes away
+        let replay = || packet(&b, &a, Packet::ReplayRequest { uuid, speed: 100 });
+        for _ in 0..MAX_REPLAYS {
+            server.process_message(session_b, replay()).unwrap();
+        }
+        assert!(matches!(server.process_message(session_b, replay()), Err(ServerError::TooManyReplays(_))));
+        server.run_due(server.next_due().unwrap());
+        assert!(matches!(server.process_message(session_b, replay()), Err(ServerError::TooManyReplays(_))));
+        server.deregister(&b, session_b);
+        assert_eq!(server.next_due(), None);
+    }
+
+    #[test]
+    fn test_backlog_limits() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        server.backlog_limits = BacklogLimits { max_packets: 3, max_age: Some(1_000_000) };
+        let (a, b) = (user("A"), user("B"));
+        let new_message = |content: &str, time| SPacket {
+            sender: a.clone(),
+            destination: Destination::User(b.clone()),
+            time,
+            packet: Packet::NewMessage {
+                uuid: Uuid::new_v4(),
+                content: content.to_string(),
+                start_time: time,
+                end_time: time,
+                reply_to: None,
+            },
+        };
+        let now = get_current_time();
+        for (content, time) in [("one", now), ("ancient", 0), ("two", now), ("three", now)] {
+            server.send_or_enqueue(vec![b.clone()], new_message(content, time)).unwrap();
         }
 
-        let result = handle.join().unwrap();
-        assert_eq!(result, "howdy".to_string());
+        // "one" was pushed out by the cap, and "ancient" is too old to deliver
+        let (_, mut rx_b) = connect(&mut server, &b);
+        let caught_up = drain(&mut rx_b);
+        assert!(matches!(caught_up.as_slice(), [
+            Packet::Missed { count: 2 },
+            Packet::NewMessage { content: two, .. },
+            Packet::NewMessage { content: three, .. },
+        ] if two == "two" && three == "three"));
+
+        // once delivered, it's gone
+        let (_, mut rx_again) = connect(&mut server, &b);
+        assert!(drain(&mut rx_again).is_empty());
+    }
+
+    #[test]
+    fn test_resume_session() {
+        let mut server = MessageServer::new(MemoryMessageDatabase::new());
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, _rx_a) = connect(&mut server, &a);
+        let (session_b, mut rx_b) = server.register(b.clone(), None).unwrap();
+        let numbered = |rx: &mut OutgoingReceiver| {
+            std::iter::from_fn(|| rx.try_recv())
+                .map(|(seq, p)| (seq, p.packet))
+                .collect::<Vec<_>>()
+        };
+        assert!(matches!(numbered(&mut rx_b).as_slice(), [(0, Packet::Welcome { .. })]));
+
+        // B sees a draft start but only confirms the Welcome before the connection drops
+        server.process_message(session_a, packet(&a, &b, Packet::StartDraft { reply_to: None })).unwrap();
+        assert!(matches!(numbered(&mut rx_b).as_slice(), [(1, Packet::NewDraft { .. })]));
+        server.process_message(session_b, packet(&b, &b, Packet::Ack { seq: 0 })).unwrap();
+        drop(rx_b);
+        server.deregister(&b, session_b);
+        assert!(!server.open_senders.contains_key(&b));
+
+        // coming back replays what wasn't acknowledged, with the same numbers
+        let (resumed, mut rx_b) = server.register(b.clone(), Some((session_b, 0))).unwrap();
+        assert_eq!(resumed, session_b);
+        assert!(matches!(numbered(&mut rx_b).as_slice(), [
+            (1, Packet::NewDraft { .. }),
+            (2, Packet::Welcome { resumed: true, .. }),
+            (3, Packet::NewDraft { .. }),
+            (4, Packet::Edit { .. }),
+        ]));
+
+        // an unknown session starts over
+        let (fresh, mut rx_fresh) = server.register(b.clone(), Some((make_session_id(), 5))).unwrap();
+        assert_ne!(fresh, session_b);
+        assert!(matches!(numbered(&mut rx_fresh).first(), Some((0, Packet::Welcome { resumed: false, .. }))));
+    }
+
+    #[rocket::async_test]
+    async fn test_server_start() {
+        let (server, shutdown) = MessageServer::start(
+            MemoryMessageDatabase::new(),
+            BacklogLimits::default(),
+            DEFAULT_DRAFT_GRACE,
+            0,
+            ShutdownDrafts::Discard,
+        );
+        let (a, b) = (user("A"), user("B"));
+        let (session_a, mut rx_a) = server.register(a.clone(), None).await.unwrap();
+        let send = |packet| Incoming::Packet(SPacket {
+            sender: a.clone(),
+            destination: Destination::User(b.clone()),
+            time: 0,
+            packet,
+        }, None);
+        server.packet(session_a, send(Packet::StartDraft { reply_to: None })).await.unwrap();
+        let uuid = loop {
+            match rx_a.next().await.unwrap().1.packet {
+                Packet::NewDraft { uuid, .. } => break uuid,
+                _ => continue,
+            }
+        };
+        let end = Packet::EndDraft { uuid, content: Some("howdy".to_string()), reply_to: None };
+        server.packet(session_a, send(end)).await.unwrap();
+        server.deregister(a.clone(), session_a).await.unwrap();
+
+        // B connects later and gets it from the backlog
+        let (session_b, mut rx_b) = server.register(b.clone(), None).await.unwrap();
+        let (_, welcome) = rx_b.next().await.unwrap();
+        assert!(matches!(welcome.packet, Packet::Welcome { .. }));
+        let (_, packet) = rx_b.next().await.unwrap();
+        assert!(matches!(&packet.packet, Packet::NewMessage { content, .. } if content == "howdy"));
+        assert_eq!(packet.sender, a);
+
+        // everything else goes through the same task
+        let created = server.accounts(|accounts| accounts.create_account(&user("C"), "hash".to_string())).await;
+        assert!(created.unwrap().is_ok());
+        server.deregister(b.clone(), session_b).await.unwrap();
+        assert!(rx_b.next().await.is_none());
+
+        // stopping closes the connections that are left, and waits for them to go
+        let (session_a, mut rx_a) = server.register(a.clone(), None).await.unwrap();
+        let stopping = rocket::tokio::spawn(async move { shutdown.stop().await });
+        while rx_a.next().await.is_some() {}
+        assert_eq!(rx_a.close_reason(), Some(CloseReason::Stopping));
+        assert!(matches!(server.register(user("C"), None).await, Err(ServerError::Stopped)));
+        server.deregister(a.clone(), session_a).await.unwrap();
+        stopping.await.unwrap();
+        assert!(matches!(server.register(a, None).await, Err(ServerError::Stopped)));
     }
 
     #[test]
-    fn channel() {
-        let (tx, rx) = rocket::futures::channel::mpsc::unbounded();
-        drop(rx);
-        tx.unbounded_send(3).unwrap();
+    fn test_shutdown() {
+        let mut server = MessageServer {
+            shutdown_drafts: ShutdownDrafts::Send,
+            ..MessageServer::new(MemoryMessageDatabase::new())
+        };
+        let (a, b, c) = (user("A"), user("B"), user("C"));
+        let (session_a, mut rx_a) = connect(&mut server, &a);
+        let (_, mut rx_b) = connect(&mut server, &b);
+        // nobody acknowledges hearing about the group
+        server.process_message(session_a, packet(&a, &a, Packet::CreateGroup { members: vec!["B".to_string()] })).unwrap();
+        // A is in the middle of typing to B, and to C who's away
+        for to in [&b, &c] {
+            server.process_message(session_a, packet(&a, to, Packet::StartDraft { reply_to: None })).unwrap();
+            let uuid = drain(&mut rx_a)
+                .into_iter()
+                .find_map(|p| match p {
+                    Packet::NewDraft { uuid, .. } => Some(uuid),
+                    _ => None,
+                })
+                .unwrap();
+            let edit = Packet::Edit { uuid, content: "almost done".to_string(), editing_draft: true, revision: 0 };
+            server.process_message(session_a, packet(&a, to, edit)).unwrap();
+        }
+        drain(&mut rx_b);
+
+        server.shut_down();
+        // the drafts went out as they were, and then the connections close
+        assert!(matches!(drain(&mut rx_b).as_slice(), [Packet::EndDraft { .. }]));
+        assert_eq!(rx_b.close_reason(), Some(CloseReason::Stopping));
+        let stored = server
+            .history(&RoomId::DM(make_user_pair(a.clone(), c.clone())), &MessageQuery::default(), &Page::default())
+            .unwrap();
+        assert_eq!(stored[0].content, "almost done");
+
+        // sessions can't be resumed after a restart, so what has to reach them waits in the backlog
+        let backlog = |server: &mut MessageServer<MemoryMessageDatabase>, uid: &UserId| -> Vec<Packet> {
+            let backlog = server.storage.take_backlog(uid, 0).unwrap();
+            backlog.packets.into_iter().map(|p| p.packet).collect()
+        };
+        // an EndDraft that wasn't acknowledged is owed as the message it became
+        let sent = |p: &Packet| matches!(p, Packet::NewMessage { content, .. } if content == "almost done");
+        let owed_a = backlog(&mut server, &a);
+        assert!(matches!(owed_a.first(), Some(Packet::GroupInfo { .. })));
+        assert_eq!(owed_a.iter().filter(|p| sent(p)).count(), 2);
+        assert!(owed_a.iter().any(|p| matches!(p, Packet::Receipt { .. })));
+        assert_eq!(owed_a.len(), 4);
+        let owed_b = backlog(&mut server, &b);
+        assert!(matches!(owed_b.as_slice(), [Packet::GroupInfo { .. }, p] if sent(p)));
+        assert!(matches!(backlog(&mut server, &c).as_slice(), [Packet::NewMessage { content, .. }] if content == "almost done"));
     }
 }
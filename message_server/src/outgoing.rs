@@ -0,0 +1,233 @@
+// The queue between the server and one connection's websocket.
+// It's bounded so a client that can't keep up doesn't make the server hold on to
+// everything sent to it: a draft Edit replaces the changes to that draft still waiting
+// in the queue, and a client that falls behind anyway is disconnected (it can resume).
+
+use crate::message_server::Outgoing;
+use crate::packet::Packet;
+use crate::protocol::MessageId;
+use rocket::futures::task::AtomicWaker;
+use rocket::futures::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Why a packet couldn't be queued. Either way the connection is closed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// The connection was already closed
+    Closed,
+    /// The client fell too far behind, so the connection was closed
+    Full,
+}
+
+/// Why the server closed a connection, for telling the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The server is shutting down
+    Stopping,
+    /// The client fell too far behind
+    FellBehind,
+}
+
+/// The server's end
+#[derive(Debug)]
+pub struct OutgoingSender {
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+/// The connection's end. Packets come out in order, except for superseded draft changes, which don't come out at all.
+#[derive(Debug)]
+pub struct OutgoingReceiver {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// The receiver, waiting for a packet
+    waker: AtomicWaker,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Outgoing>,
+    closed: bool,
+    /// Set if the server closed it on purpose
+    reason: Option<CloseReason>,
+}
+
+/// A queue holding at most `capacity` packets
+pub fn channel(capacity: usize) -> (OutgoingSender, OutgoingReceiver) {
+    let shared = Arc::new(Shared::default());
+    (
+        OutgoingSender { shared: Arc::clone(&shared), capacity },
+        OutgoingReceiver { shared },
+    )
+}
+
+impl OutgoingSender {
+    /// Queue a packet, dropping whatever it supersedes. If that leaves no room, the connection is closed instead.
+    pub fn send(&self, out: Outgoing) -> Result<(), QueueError> {
+        let result = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(QueueError::Closed);
+            }
+            if let Some(uuid) = draft_edit(&out.1.packet) {
+                state.queue.retain(|(_, queued)| !changes_draft(&queued.packet, uuid));
+            }
+            if state.queue.len() >= self.capacity {
+                state.closed = true;
+                state.reason = Some(CloseReason::FellBehind);
+                state.queue.clear();
+                Err(QueueError::Full)
+            } else {
+                state.queue.push_back(out);
+                Ok(())
+            }
+        };
+        self.shared.waker.wake();
+        result
+    }
+
+    /// Whether the connection is gone, or was cut off for falling behind
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Close the connection once what's already queued has gone out
+    pub fn close(&self, reason: CloseReason) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.closed = true;
+            state.reason.get_or_insert(reason);
+        }
+        self.shared.waker.wake();
+    }
+}
+
+impl OutgoingReceiver {
+    /// The next packet if there is one already
+    pub fn try_recv(&mut self) -> Option<Outgoing> {
+        self.shared.state.lock().unwrap().queue.pop_front()
+    }
+
+    /// Why the server closed the connection, if it did
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.shared.state.lock().unwrap().reason
+    }
+}
+
+impl Stream for OutgoingReceiver {
+    type Item = Outgoing;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Outgoing>> {
+        // registered before looking so a packet queued in between still wakes us
+        self.shared.waker.register(cx.waker());
+        let mut state = self.shared.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(out) => Poll::Ready(Some(out)),
+            None if state.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for OutgoingSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.waker.wake();
+    }
+}
+
+impl Drop for OutgoingReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+    }
+}
+
+/// The draft a packet replaces the whole content of, if it does
+fn draft_edit(packet: &Packet) -> Option<MessageId> {
+    match packet {
+        Packet::Edit { uuid, editing_draft: true, .. } => Some(*uuid),
+        _ => None,
+    }
+}
+
+/// Whether a packet is a change to the draft `uuid`, which a later full edit makes pointless
+fn changes_draft(packet: &Packet, uuid: MessageId) -> bool {
+    match packet {
+        Packet::Edit { uuid: edited, editing_draft: true, .. } | Packet::Delta { uuid: edited, .. } => *edited == uuid,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::identity::make_user_id;
+    use crate::outgoing::{channel, CloseReason, QueueError};
+    use crate::packet::{Destination, Packet, SPacket};
+    use crate::protocol::EditOp;
+    use rocket::futures::StreamExt;
+    use uuid::Uuid;
+
+    fn out(seq: u64, packet: Packet) -> (u64, SPacket) {
+        let user = make_user_id("A".to_string());
+        (seq, SPacket { sender: user.clone(), destination: Destination::User(user), time: 0, packet })
+    }
+
+    fn edit(uuid: Uuid, content: &str) -> Packet {
+        Packet::Edit { uuid, content: content.to_string(), editing_draft: true, revision: 0 }
+    }
+
+    #[rocket::async_test]
+    async fn superseded_edits_are_dropped() {
+        let (tx, mut rx) = channel(3);
+        let (draft, other) = (Uuid::new_v4(), Uuid::new_v4());
+        tx.send(out(0, edit(draft, "h"))).unwrap();
+        tx.send(out(1, edit(other, "x"))).unwrap();
+        let delta = Packet::Delta { uuid: draft, revision: 1, op: EditOp::Insert { offset: 1, text: "i".to_string() } };
+        tx.send(out(2, delta)).unwrap();
+        // only the latest content of a draft is worth sending
+        tx.send(out(3, edit(draft, "hi!"))).unwrap();
+        tx.send(out(4, edit(draft, "hi!!"))).unwrap();
+        let sent = Packet::Edit { uuid: other, content: "y".to_string(), editing_draft: false, revision: 1 };
+        tx.send(out(5, sent)).unwrap();
+
+        let seqs: Vec<u64> = [rx.next().await, rx.next().await, rx.next().await].map(|o| o.unwrap().0).into();
+        assert_eq!(seqs, vec![1, 4, 5]);
+        assert!(rx.try_recv().is_none());
+        drop(tx);
+        assert!(rx.next().await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn falling_behind_closes_the_connection() {
+        let (tx, mut rx) = channel(2);
+        let message = |seq| out(seq, Packet::DiscardDraft { uuid: Uuid::new_v4() });
+        tx.send(message(0)).unwrap();
+        tx.send(message(1)).unwrap();
+        assert_eq!(tx.send(message(2)), Err(QueueError::Full));
+        assert_eq!(tx.send(message(3)), Err(QueueError::Closed));
+        assert!(rx.next().await.is_none());
+        assert_eq!(rx.close_reason(), Some(CloseReason::FellBehind));
+
+        let (tx, rx) = channel(2);
+        drop(rx);
+        assert_eq!(tx.send(message(0)), Err(QueueError::Closed));
+    }
+
+    #[rocket::async_test]
+    async fn closing_lets_the_queue_drain() {
+        let (tx, mut rx) = channel(2);
+        tx.send(out(0, Packet::DiscardDraft { uuid: Uuid::new_v4() })).unwrap();
+        tx.close(CloseReason::Stopping);
+        assert_eq!(tx.send(out(1, Packet::DiscardDraft { uuid: Uuid::new_v4() })), Err(QueueError::Closed));
+        assert_eq!(rx.next().await.map(|(seq, _)| seq), Some(0));
+        assert!(rx.next().await.is_none());
+        assert_eq!(rx.close_reason(), Some(CloseReason::Stopping));
+    }
+}
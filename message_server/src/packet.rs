@@ -1,10 +1,12 @@
 use std::time::SystemTime;
-use crate::identity::{make_user_id, UserId};
+use crate::identity::{make_user_id, GroupChatId, UserId};
 use rocket_ws::Message;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use uuid;
-use crate::protocol::{MessageId, Timestamp};
+use crate::protocol::{
+    self, DraftVisibility, EditOp, MessageId, MessageVersion, PresenceStatus, Receipt, ReceiptStatus, Revision, Timestamp,
+};
 // ------------------------- Web Packets -----------------------------
 
 /// This is what is sent on the websocket
@@ -14,41 +16,56 @@ pub struct WebPacket {
     destination: WebDest,
     sender: Option<String>, // only used going toward client
     timestamp: Option<Timestamp>, // only used going toward client
+    seq: Option<Seq>, // only used going toward client
+    /// Chosen by the client to match the server's Accepted or Error answer to this packet
+    request_id: Option<RequestId>, // only used going toward server
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 enum WebDest {
     User(String),
-    // Group(Uuid) // sometime later for group chats
+    Group(#[serde(with = "uuid::serde::compact")] Uuid),
 }
 
 // ----------------------------- Common Usage ----------------------------
 
 /// Packet Message
-#[derive(Deserialize, Serialize, Eq, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone)]
 pub enum Packet {
-    ///
+    /// A finished message, e.g. one that was sent while the recipient was away. Only the server sends these
     NewMessage {
         #[serde(with = "uuid::serde::compact")]
         uuid: Uuid,
         content: String,
         start_time: Timestamp,
-        end_time: Timestamp
+        end_time: Timestamp,
+        /// The message this replies to, if it's a reply
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
+    },
+    /// A user only has one draft at a time in a conversation - the last thing they typed.
+    /// `reply_to` makes it a reply to an earlier message of the same conversation
+    StartDraft {
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
     },
-    // SyncMessage(Uuid, String), // to be used to sync database w/ chats
-    /// A user only has one draft at a time in a conversation - the last thing they typed
-    StartDraft,
     /// Sent back to the sender after starting a new draft
     NewDraft {
         #[serde(with = "uuid::serde::compact")]
         uuid: MessageId,
         // for catch-up, in case they missed the draft being started
-        start_time: Timestamp
+        start_time: Timestamp,
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
     },
+    /// From the sender, `reply_to` (if set) replaces whatever the draft was replying to.
+    /// Relayed with what it ended up replying to
     EndDraft {
         #[serde(with = "uuid::serde::compact")]
         uuid: MessageId,
         content: Option<String>, // just to sync easier
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
     },
     DiscardDraft {
         #[serde(with = "uuid::serde::compact")]
@@ -58,14 +75,255 @@ pub enum Packet {
         #[serde(with = "uuid::serde::compact")]
         uuid: MessageId,
         content: String,
-        editing_draft: bool
+        editing_draft: bool,
+        /// The revision this edit made for drafts, or the version for sent messages
+        /// (the original being 0). Filled in by the server
+        #[serde(default)]
+        revision: Revision,
+    },
+    /// A single change to a draft, made against `revision`. Cheaper than a full Edit while typing.
+    /// Watchers get it relayed as-is (or a full Edit, if the server held back earlier changes to keep
+    /// to its update rate); a sender whose revision was out of date gets a full Edit back.
+    Delta {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        revision: Revision,
+        op: EditOp,
+    },
+    /// Ask for stored history of the conversation with the destination. Answered by SyncMessages
+    SyncRequest {
+        /// Only messages older than this one; the newest messages if missing
+        #[serde(default, with = "compact_option")]
+        before: Option<MessageId>,
+        /// At least 1, and capped by the server
+        limit: usize,
+    },
+    /// A page of history, oldest first. `more` is set when there are older messages left
+    SyncMessages {
+        messages: Vec<MessageRecord>,
+        more: bool,
+    },
+    /// Start a group chat with these users (and yourself). The destination is ignored,
+    /// so send it to yourself. Everyone in the group gets a GroupInfo with the new id.
+    CreateGroup {
+        members: Vec<String>,
+    },
+    /// Sent to a group by one of its members
+    AddMember {
+        user: String,
+    },
+    /// Sent to a group by the member who wants out
+    LeaveGroup,
+    /// Current members of the group in the destination, sent whenever that changes
+    GroupInfo {
+        members: Vec<String>,
+    },
+    /// The client has handled every packet on this connection up to and including `seq`
+    Ack {
+        seq: Seq,
+    },
+    /// Some packets queued while the user was away were thrown away, for being too old
+    /// or too many. Comes before whatever is left of the backlog.
+    Missed {
+        count: usize,
+    },
+    /// Sent once a connection is ready, after anything replayed for a resumed session.
+    /// `resumed` is false if the session asked for couldn't be picked up again,
+    /// in which case the client should sync its history.
+    Welcome {
+        #[serde(with = "uuid::serde::compact")]
+        session: Uuid,
+        resumed: bool,
+    },
+    /// Ask for every version of a sent message in the conversation with the destination.
+    /// Answered by EditHistory
+    EditHistoryRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    /// Every version of a message, as first sent first and as it is now last
+    EditHistory {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        versions: Vec<MessageVersion>,
+    },
+    /// Something the client sent was refused. `uuid` is the message it was about, if any,
+    /// and `request_id` the one the client gave the packet, if it did
+    Error {
+        code: ErrorCode,
+        #[serde(default, with = "compact_option")]
+        uuid: Option<MessageId>,
+        #[serde(default)]
+        request_id: Option<RequestId>,
+    },
+    /// A packet the client gave a request id went through
+    Accepted {
+        request_id: RequestId,
+    },
+    /// Take back a sent message. Only its sender may; everyone in the conversation
+    /// (including the sender's other connections) gets this relayed.
+    DeleteMessage {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    /// Choose how much the destination sees of your drafts while you type them, or with
+    /// `everywhere`, in every conversation without a choice of its own. None goes back to the
+    /// default (Live, everywhere). Takes effect from the next draft, and is echoed to all your connections.
+    SetDraftVisibility {
+        visibility: Option<DraftVisibility>,
+        #[serde(default)]
+        everywhere: bool,
+    },
+    /// Ask to watch a finished message in the conversation with the destination being typed again.
+    /// `speed` is a percentage of the pace it was typed at (100 if missing). Answered by Replay packets
+    ReplayRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        #[serde(default = "full_speed")]
+        speed: u32,
+    },
+    /// One change from how a message was typed, sent at the pace it was first made.
+    /// A replay starts from empty content; `time` is when the change originally happened,
+    /// and `last` is set on the final one.
+    Replay {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        time: Timestamp,
+        op: EditOp,
+        last: bool,
+    },
+    /// You've seen the message `uuid` in the conversation with the destination.
+    /// Its sender gets a Receipt
+    MarkRead {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    /// A message you sent got as far as `status` (at `time`) with whoever this is from.
+    /// Delivered ones are sent by the server as soon as the message reaches one of their connections
+    Receipt {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        status: ReceiptStatus,
+        time: Timestamp,
+    },
+    /// Ask how far a message you sent in the conversation with the destination has got.
+    /// Answered by Receipts
+    ReceiptsRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    /// Where a message has got with everyone it reached
+    Receipts {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        receipts: Vec<ReceiptRecord>,
+    },
+    /// Ask for every reply to a message in the conversation with the destination. Answered by Replies
+    RepliesRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    /// Every stored message replying to `uuid`, oldest first
+    Replies {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        messages: Vec<MessageRecord>,
+    },
+    /// Whether this connection is being used. Clients send it when that changes (e.g. the window
+    /// is hidden or shown again); the destination is ignored. Any other packet counts as activity too
+    Heartbeat {
+        idle: bool,
+    },
+    /// Also get Presence packets for these users, on top of the people you talk to, until you
+    /// unsubscribe or your last connection closes. Answered with their current Presence (so it's also
+    /// how to find out who's around after connecting); the destination is ignored
+    SubscribePresence {
+        users: Vec<String>,
+    },
+    /// Stop getting Presence packets for these users, unless you talk to them
+    UnsubscribePresence {
+        users: Vec<String>,
+    },
+    /// Whoever this is from is now `status`. `last_seen` is when they were last active,
+    /// if they aren't online and have been seen since the server started
+    Presence {
+        status: PresenceStatus,
+        last_seen: Option<Timestamp>,
     },
 }
 
+/// Why a request was refused, for clients to act on
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The packet couldn't be read
+    BadPacket,
+    /// Only the server sends that kind of packet
+    ServerOnly,
+    /// No draft (with that id) in that conversation
+    MissingDraft,
+    /// The draft is being typed on another connection
+    NotDraftOwner,
+    /// EndDraft named a different draft than the one being typed
+    BadEndDraft,
+    /// No such message in that conversation
+    MissingMessage,
+    /// No such conversation
+    MissingConversation,
+    /// Only the sender of a message may change it
+    NotAuthor,
+    MessageDeleted,
+    /// Only members can do that in a group
+    NotAMember,
+    /// That only works in a group
+    NotAGroup,
+    /// The delta was made against an older revision of the draft; a snapshot of it was sent back
+    StaleRevision,
+    /// The delta reaches past the end of the draft; a snapshot of it was sent back
+    OutOfBounds,
+    /// Too many replays are already playing on this connection
+    TooManyReplays,
+    /// Something went wrong on the server's end
+    Internal,
+}
+
+/// A stored message, as handed to clients catching up on history
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone)]
+pub struct MessageRecord {
+    #[serde(with = "uuid::serde::compact")]
+    pub uuid: MessageId,
+    pub sender: String,
+    pub content: String,
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    /// When it was last edited, if it was. See EditHistoryRequest
+    #[serde(default)]
+    pub edited: Option<Timestamp>,
+    /// Set if the message was deleted, in which case there's no content
+    #[serde(default)]
+    pub deleted: Option<Timestamp>,
+    /// The message this replies to, if it's a reply
+    #[serde(default, with = "compact_option")]
+    pub reply_to: Option<MessageId>,
+}
+
+/// How far a message has got with one user, as handed to clients
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone)]
+pub struct ReceiptRecord {
+    pub user: String,
+    pub status: ReceiptStatus,
+    pub time: Timestamp,
+}
+
 // ----------------------- Server Packets -------------------------
 
+/// Position of a packet in everything sent on one connection, counting from 0.
+/// Clients that fall behind can see gaps, where draft edits were superseded before being sent
+pub type Seq = u64;
+/// Picked by the client to tell its requests apart
+pub type RequestId = u64;
+
 /// Correctly annotated & authenticated packet
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct SPacket {
     pub sender: UserId,
     pub destination: Destination,
@@ -77,15 +335,35 @@ pub struct SPacket {
 #[derive(Hash, PartialEq, Eq, Debug, Clone)]
 pub enum Destination {
     User(UserId),
-    // Group(...?) // future use
+    Group(GroupChatId),
 }
 
 #[derive(Debug)]
 pub enum PacketError {
     Serde(serde_json::Error),
+    /// A packet from the client that couldn't be parsed, with its request id if that much could be read
+    Unreadable(serde_json::Error, Option<RequestId>),
     WrongType(Message),
 }
 
+/// Like uuid::serde::compact, for optional ids
+mod compact_option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use uuid::Uuid;
+
+    pub fn serialize<S: Serializer>(uuid: &Option<Uuid>, serializer: S) -> Result<S::Ok, S::Error> {
+        uuid.map(|u| *u.as_bytes()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Uuid>, D::Error> {
+        Ok(Option::<[u8; 16]>::deserialize(deserializer)?.map(Uuid::from_bytes))
+    }
+}
+
+fn full_speed() -> u32 {
+    100
+}
+
 pub fn get_current_time() -> Timestamp {
     let now = SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -99,7 +377,8 @@ pub fn make_uuid() -> Uuid {
 
 pub fn make_server_packet(webpacket: WebPacket, sender: UserId) -> SPacket {
     let destination = match &webpacket.destination {
-        WebDest::User(uid) => Destination::User(make_user_id(uid.clone()))
+        WebDest::User(uid) => Destination::User(make_user_id(uid.clone())),
+        WebDest::Group(gid) => Destination::Group(GroupChatId::from(*gid)),
     };
     // we ignore webpacket.sender and webpacket.timestamp
     // because that's only for sending it back
@@ -111,17 +390,61 @@ pub fn make_server_packet(webpacket: WebPacket, sender: UserId) -> SPacket {
     }
 }
 
-pub fn make_webpacket(spacket: SPacket) -> WebPacket {
+pub fn make_webpacket(spacket: SPacket, seq: Seq) -> WebPacket {
     let destination = match spacket.destination {
-        Destination::User(uid) => WebDest::User(uid.to_string())
+        Destination::User(uid) => WebDest::User(uid.to_string()),
+        Destination::Group(gid) => WebDest::Group(gid.to_uuid()),
     };
     WebPacket {
         destination,
         sender: Some(spacket.sender.to_string()),
         timestamp: Some(spacket.time),
+        seq: Some(seq),
+        request_id: None,
         content: spacket.packet,
     }
 }
+
+impl WebPacket {
+    pub fn request_id(&self) -> Option<RequestId> {
+        self.request_id
+    }
+}
+
+impl PacketError {
+    /// The request id of the packet that couldn't be read, if there was one
+    pub fn request_id(&self) -> Option<RequestId> {
+        match self {
+            PacketError::Unreadable(_, request_id) => *request_id,
+            PacketError::Serde(_) | PacketError::WrongType(_) => None,
+        }
+    }
+}
+impl From<&protocol::Message> for MessageRecord {
+    fn from(message: &protocol::Message) -> Self {
+        MessageRecord {
+            uuid: message.id,
+            sender: message.sender.to_string(),
+            content: message.content.clone(),
+            start_time: message.start_time,
+            end_time: message.end_time,
+            edited: message.edited,
+            deleted: message.deleted,
+            reply_to: message.reply_to,
+        }
+    }
+}
+
+impl From<Receipt> for ReceiptRecord {
+    fn from(receipt: Receipt) -> Self {
+        ReceiptRecord {
+            user: receipt.user.to_string(),
+            status: receipt.status,
+            time: receipt.time,
+        }
+    }
+}
+
 pub trait RoutingInfo {
     fn get_to_from(&self) -> (Destination, UserId);
 }
@@ -136,9 +459,12 @@ impl TryFrom<Message> for WebPacket {
     type Error = PacketError;
     fn try_from(value: Message) -> Result<Self, Self::Error> {
         match value {
-            Message::Text(txt) => {
-                serde_json::from_str::<WebPacket>(&txt).map_err(|e| PacketError::Serde(e))
-            }
+            Message::Text(txt) => serde_json::from_str::<WebPacket>(&txt).map_err(|err| {
+                let request_id = serde_json::from_str::<serde_json::Value>(&txt)
+                    .ok()
+                    .and_then(|v| v.get("request_id")?.as_u64());
+                PacketError::Unreadable(err, request_id)
+            }),
             v => Err(PacketError::WrongType(v)),
         }
     }
@@ -148,7 +474,7 @@ impl TryFrom<WebPacket> for Message {
     type Error = PacketError;
     fn try_from(value: WebPacket) -> Result<Self, Self::Error> {
         serde_json::to_string(&value)
-            .map(|s| Message::Text(s))
-            .map_err(|e| PacketError::Serde(e))
+            .map(Message::Text)
+            .map_err(PacketError::Serde)
     }
 }
@@ -1,15 +1,59 @@
+use std::iter;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::identity::UserId;
 
 /// Unix microseconds. See get_current_time()
 pub type Timestamp = u64;
 pub type MessageId = Uuid;
+/// Counts the changes made to a draft, starting from 0 when it's created
+pub type Revision = u64;
 
 #[derive(Debug)]
 pub struct Draft {
     pub id: MessageId,
     pub content: String,
     pub start_time: Timestamp,
+    pub revision: Revision,
+    /// Every change so far, for replaying how the message was typed
+    pub timeline: Vec<Keystroke>,
+    /// The message it's replying to, if any
+    pub reply_to: Option<MessageId>,
+}
+
+/// One change to a draft and when it happened
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Keystroke {
+    pub time: Timestamp,
+    pub op: EditOp,
+}
+
+/// One change to a draft. Offsets and lengths count characters (unicode scalar values), not bytes.
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone)]
+pub enum EditOp {
+    Insert { offset: usize, text: String },
+    Delete { offset: usize, len: usize },
+}
+
+/// How much of someone's draft the other side of the conversation gets to see while it's typed.
+/// Everyone gets the finished message either way.
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub enum DraftVisibility {
+    /// Every change as it's made
+    #[default]
+    Live,
+    /// Only that a draft was started (or thrown away), not what's in it
+    Typing,
+    /// Nothing until it's sent
+    Hidden,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The change was made against another revision: (current, received)
+    StaleRevision(Revision, Revision),
+    /// The change reaches past the end of the draft
+    OutOfBounds,
 }
 
 /// Should be similar to frontend Message, but slightly more space conscious.
@@ -21,16 +65,156 @@ pub struct Message {
     pub id: MessageId,
     pub start_time: Timestamp,
     pub end_time: Timestamp,
+    /// When it was last edited, if it ever was
+    pub edited: Option<Timestamp>,
+    /// When the sender took it back. A deleted message keeps its place but has no content
+    pub deleted: Option<Timestamp>,
+    /// The message in the same room it replies to, if it's a reply
+    pub reply_to: Option<MessageId>,
+}
+
+/// How far a message has got with someone it was sent to. Read implies delivered
+#[derive(Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub enum ReceiptStatus {
+    /// It reached one of their connections
+    Delivered,
+    /// They told us they've seen it
+    Read,
+}
+
+/// How far a message has got with one member of its room, and since when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub user: UserId,
+    pub status: ReceiptStatus,
+    pub time: Timestamp,
+}
+
+/// Whether someone can be reached right now, as their conversation partners see it
+#[derive(Deserialize, Serialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum PresenceStatus {
+    /// At least one of their connections is in use
+    Online,
+    /// Connected, but every connection told us it's idle
+    Idle,
+    Offline,
+}
+
+/// What a sent message said at some point, and since when
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageVersion {
+    pub content: String,
+    pub time: Timestamp,
 }
 
 impl Draft {
+    /// Apply `op`, which was made against revision `base`, returning the new revision.
+    /// Only one connection types in a draft, so a change made against any other revision
+    /// is rejected rather than transformed.
+    pub fn apply(&mut self, base: Revision, op: &EditOp, time: Timestamp) -> Result<Revision, DeltaError> {
+        if base != self.revision {
+            return Err(DeltaError::StaleRevision(self.revision, base));
+        }
+        let byte_offset = |chars: usize| {
+            self.content
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(iter::once(self.content.len()))
+                .nth(chars)
+                .ok_or(DeltaError::OutOfBounds)
+        };
+        match op {
+            EditOp::Insert { offset, text } => {
+                let at = byte_offset(*offset)?;
+                self.content.insert_str(at, text);
+            }
+            EditOp::Delete { offset, len } => {
+                let end = offset.checked_add(*len).ok_or(DeltaError::OutOfBounds)?;
+                let range = byte_offset(*offset)?..byte_offset(end)?;
+                self.content.replace_range(range, "");
+            }
+        }
+        self.timeline.push(Keystroke { time, op: op.clone() });
+        self.revision += 1;
+        Ok(self.revision)
+    }
+
+    /// Replace everything at once, as a full Edit does. Returns the new revision.
+    /// Only the part between what the old and new content start and end with is recorded as changed.
+    pub fn set_content(&mut self, content: String, time: Timestamp) -> Revision {
+        let old: Vec<char> = self.content.chars().collect();
+        let new: Vec<char> = content.chars().collect();
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let removed = old.len() - prefix - suffix;
+        if removed > 0 {
+            self.timeline.push(Keystroke { time, op: EditOp::Delete { offset: prefix, len: removed } });
+        }
+        if new.len() > prefix + suffix {
+            let text = new[prefix..new.len() - suffix].iter().collect();
+            self.timeline.push(Keystroke { time, op: EditOp::Insert { offset: prefix, text } });
+        }
+        self.content = content;
+        self.revision += 1;
+        self.revision
+    }
+
     pub fn into_message(self, sender: UserId, time: Timestamp) -> Message {
         Message {
             sender,
             content: self.content,
             id: self.id,
             start_time: self.start_time,
-            end_time: time
+            end_time: time,
+            edited: None,
+            deleted: None,
+            reply_to: self.reply_to,
         }
     }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::{DeltaError, Draft, EditOp};
+    use uuid::Uuid;
+
+    #[test]
+    fn deltas_apply_in_order() {
+        let mut draft = Draft {
+            id: Uuid::new_v4(),
+            content: String::new(),
+            start_time: 0,
+            revision: 0,
+            timeline: vec![],
+            reply_to: None,
+        };
+        let insert = |offset, text: &str| EditOp::Insert { offset, text: text.to_string() };
+        assert_eq!(draft.apply(0, &insert(0, "héllo"), 1), Ok(1));
+        assert_eq!(draft.apply(1, &insert(5, " wörld"), 2), Ok(2));
+        assert_eq!(draft.apply(2, &EditOp::Delete { offset: 1, len: 4 }, 3), Ok(3));
+        assert_eq!(draft.content, "h wörld");
+
+        // nothing changes when a delta doesn't fit
+        assert_eq!(draft.apply(1, &insert(0, "x"), 4), Err(DeltaError::StaleRevision(3, 1)));
+        assert_eq!(draft.apply(3, &insert(8, "x"), 4), Err(DeltaError::OutOfBounds));
+        assert_eq!(draft.apply(3, &EditOp::Delete { offset: 5, len: usize::MAX }, 4), Err(DeltaError::OutOfBounds));
+        assert_eq!((draft.content.as_str(), draft.revision), ("h wörld", 3));
+
+        assert_eq!(draft.set_content("again".to_string(), 5), 4);
+        // the timeline replays to the same place
+        let times: Vec<u64> = draft.timeline.iter().map(|k| k.time).collect();
+        assert_eq!(times, vec![1, 2, 3, 5, 5]);
+        assert_eq!(draft.timeline.last().unwrap().op, insert(0, "again"));
+
+        // only what changed in the middle is recorded
+        draft.set_content("again and again".to_string(), 6);
+        draft.set_content("again, again".to_string(), 7);
+        let ops: Vec<EditOp> = draft.timeline[5..].iter().map(|k| k.op.clone()).collect();
+        assert_eq!(ops, vec![insert(5, " and again"), EditOp::Delete { offset: 5, len: 4 }, insert(5, ",")]);
+    }
 }
\ No newline at end of file
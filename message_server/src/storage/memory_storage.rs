@@ -1,55 +1,216 @@
 
 // implementation of message storage as in-memory :)
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
-use crate::identity::{GroupChatId, UserId, UserPair};
-use crate::packet::Destination;
-use crate::protocol::{Message, MessageId, Timestamp};
+use std::ops::Bound::{Excluded, Unbounded};
+use crate::identity::{make_user_pair, GroupChatId, UserId, UserPair};
+use crate::packet::{Destination, SPacket};
+use crate::protocol::{
+    DraftVisibility, Keystroke, Message, MessageId, MessageVersion, Receipt, ReceiptStatus, Revision, Timestamp,
+};
 use crate::storage;
-use crate::storage::{MessageDAOError, MessageFilter, MessageRoomDAO, MessagesDAO, RoomId};
+use crate::storage::{carries_content, AccountDAO, Backlog, BacklogDAO, MessageDAOError, MessageFilter, MessageRoomDAO, MessagesDAO, RoomId, SettingsDAO};
+use crate::storage::query::{Cursor, Page};
 use crate::storage::Result;
 
 /// Contains messages in a room. Either a dm or a group chat.
 pub struct MemoryMessageRoom {
     members: HashSet<UserId>,
     is_dm: bool,
-    message_order: BTreeMap<Timestamp, MessageId>,
+    /// (start_time, end_time, id), so messages typed at the same time still sort stably
+    message_order: BTreeSet<OrderKey>,
     messages: HashMap<MessageId, Message>,
+    /// how each message was typed, if it was recorded
+    timelines: HashMap<MessageId, Vec<Keystroke>>,
+    /// what edited messages said before, oldest first
+    versions: HashMap<MessageId, Vec<MessageVersion>>,
+    /// how far each message got with each member, and since when
+    receipts: HashMap<MessageId, BTreeMap<UserId, (ReceiptStatus, Timestamp)>>,
 }
 
+/// (start_time, end_time, id)
+type OrderKey = (Timestamp, Timestamp, MessageId);
+
 /// Contains all messages
 pub struct MemoryMessageDatabase {
     direct_messages: HashMap<UserPair, MemoryMessageRoom>,
     group_messages: HashMap<GroupChatId, MemoryMessageRoom>,
+    /// (waiting packets, how many were dropped)
+    backlogs: HashMap<UserId, (VecDeque<SPacket>, usize)>,
+    /// password hash of each account
+    accounts: HashMap<UserId, String>,
+    /// token hash -> (user, expiry)
+    tokens: HashMap<String, (UserId, Timestamp)>,
+    /// (user, conversation or None for everywhere) -> what they chose
+    draft_visibility: HashMap<(UserId, Option<Destination>), DraftVisibility>,
 }
 
-impl MessageRoomDAO for MemoryMessageRoom {
-    fn new<M: Iterator<Item = UserId>>(members: M, is_dm: bool) -> MemoryMessageRoom {
+impl MemoryMessageRoom {
+    pub fn new<M: Iterator<Item = UserId>>(members: M, is_dm: bool) -> MemoryMessageRoom {
         MemoryMessageRoom {
             members: members.collect(),
             is_dm,
             message_order: Default::default(),
             messages: Default::default(),
+            timelines: Default::default(),
+            versions: Default::default(),
+            receipts: Default::default(),
         }
     }
 
-    fn get_messages<F: MessageFilter>(&self, filter: &F) -> Vec<&Message> {
-        todo!()
+    pub fn is_dm(&self) -> bool {
+        self.is_dm
     }
 
-    fn add_message(&mut self, message: Message) -> storage::Result<()> {
-        self.messages.insert(message.id, message);
-        Ok(())
+    pub fn check_member(&self, uid: &UserId) -> Result<()> {
+        if self.members.contains(uid) {
+            Ok(())
+        } else {
+            Err(MessageDAOError::NotAMember(uid.clone()))
+        }
     }
 
-    fn get_message_mut(&mut self, m_id: MessageId) -> Option<&mut Message> {
+    pub fn get_message_mut(&mut self, m_id: MessageId) -> Option<&mut Message> {
         self.messages.get_mut(&m_id)
     }
+}
+
+impl MessageRoomDAO for MemoryMessageRoom {
+    fn get_messages<F: MessageFilter>(&self, filter: &F, page: &Page) -> Result<Vec<&Message>> {
+        let key = |m_id| self.messages.get(&m_id)
+            .map(order_key)
+            .ok_or(MessageDAOError::MissingMessageId(m_id));
+        let walk: Box<dyn Iterator<Item = &OrderKey>> = match page.cursor {
+            Cursor::Start => Box::new(self.message_order.iter()),
+            Cursor::End => Box::new(self.message_order.iter().rev()),
+            Cursor::After(m_id) => Box::new(self.message_order.range((Excluded(key(m_id)?), Unbounded))),
+            Cursor::Before(m_id) => Box::new(self.message_order.range(..key(m_id)?).rev()),
+        };
+        Ok(page.collect(
+            walk.map(|(_, _, m_id)| &self.messages[m_id])
+                .filter(|m| filter.include_message(m)),
+        ))
+    }
+
+    fn add_message(&mut self, message: Message) -> storage::Result<()> {
+        self.check_member(&message.sender)?;
+        if let Some(old) = self.messages.get(&message.id) {
+            self.message_order.remove(&order_key(old));
+        }
+        self.message_order.insert(order_key(&message));
+        self.messages.insert(message.id, message);
+        Ok(())
+    }
 
     fn get_message(&self, m_id: MessageId) -> Option<&Message> {
         self.messages.get(&m_id)
     }
+
+    fn edit_message(&mut self, m_id: MessageId, new_content: String, time: Timestamp) -> Result<Revision> {
+        let message = self.messages.get_mut(&m_id).ok_or(MessageDAOError::MissingMessageId(m_id))?;
+        if message.deleted.is_some() {
+            return Err(MessageDAOError::MessageDeleted(m_id));
+        }
+        let previous = MessageVersion {
+            time: message.edited.unwrap_or(message.end_time),
+            content: std::mem::replace(&mut message.content, new_content),
+        };
+        message.edited = Some(time);
+        let versions = self.versions.entry(m_id).or_default();
+        versions.push(previous);
+        Ok(versions.len() as Revision)
+    }
+
+    fn get_versions(&self, m_id: MessageId) -> Result<Vec<MessageVersion>> {
+        let message = self.messages.get(&m_id).ok_or(MessageDAOError::MissingMessageId(m_id))?;
+        if message.deleted.is_some() {
+            return Err(MessageDAOError::MessageDeleted(m_id));
+        }
+        let mut versions = self.versions.get(&m_id).cloned().unwrap_or_default();
+        versions.push(MessageVersion {
+            content: message.content.clone(),
+            time: message.edited.unwrap_or(message.end_time),
+        });
+        Ok(versions)
+    }
+
+    fn remove_message(&mut self, m_id: MessageId, time: Timestamp) -> Result<()> {
+        let message = self.get_message_mut(m_id).ok_or(MessageDAOError::MissingMessageId(m_id))?;
+        if message.deleted.is_some() {
+            return Err(MessageDAOError::MessageDeleted(m_id));
+        }
+        message.content.clear();
+        message.deleted = Some(time);
+        self.timelines.remove(&m_id);
+        self.versions.remove(&m_id);
+        Ok(())
+    }
+
+    fn members(&self) -> &HashSet<UserId> {
+        &self.members
+    }
+
+    fn add_member(&mut self, uid: UserId) -> Result<()> {
+        if self.is_dm {
+            return Err(MessageDAOError::FixedMembership);
+        }
+        self.members.insert(uid);
+        Ok(())
+    }
+
+    fn remove_member(&mut self, uid: &UserId) -> Result<()> {
+        if self.is_dm {
+            return Err(MessageDAOError::FixedMembership);
+        }
+        self.check_member(uid)?;
+        self.members.remove(uid);
+        Ok(())
+    }
+
+    fn set_timeline(&mut self, m_id: MessageId, timeline: Vec<Keystroke>) -> Result<()> {
+        if !self.messages.contains_key(&m_id) {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        self.timelines.insert(m_id, timeline);
+        Ok(())
+    }
+
+    fn get_timeline(&self, m_id: MessageId) -> Result<Vec<Keystroke>> {
+        if !self.messages.contains_key(&m_id) {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        Ok(self.timelines.get(&m_id).cloned().unwrap_or_default())
+    }
+
+    fn set_receipt(&mut self, m_id: MessageId, member: &UserId, status: ReceiptStatus, time: Timestamp) -> Result<bool> {
+        if !self.messages.contains_key(&m_id) {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        self.check_member(member)?;
+        let receipts = self.receipts.entry(m_id).or_default();
+        if receipts.get(member).is_some_and(|(known, _)| *known >= status) {
+            return Ok(false);
+        }
+        receipts.insert(member.clone(), (status, time));
+        Ok(true)
+    }
+
+    fn get_receipts(&self, m_id: MessageId) -> Result<Vec<Receipt>> {
+        if !self.messages.contains_key(&m_id) {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        Ok(self.receipts
+            .get(&m_id)
+            .into_iter()
+            .flatten()
+            .map(|(user, (status, time))| Receipt { user: user.clone(), status: *status, time: *time })
+            .collect())
+    }
+}
+
+fn order_key(message: &Message) -> OrderKey {
+    (message.start_time, message.end_time, message.id)
 }
 
 impl MemoryMessageDatabase {
@@ -57,9 +218,20 @@ impl MemoryMessageDatabase {
         MemoryMessageDatabase {
             group_messages: HashMap::new(),
             direct_messages: HashMap::new(),
+            backlogs: HashMap::new(),
+            accounts: HashMap::new(),
+            tokens: HashMap::new(),
+            draft_visibility: HashMap::new(),
         }
     }
 }
+
+impl Default for MemoryMessageDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MessagesDAO for MemoryMessageDatabase {
     type RoomDAO = MemoryMessageRoom;
     fn add_message(&mut self, message: Message, destination: Destination) -> Result<()> {
@@ -67,21 +239,32 @@ impl MessagesDAO for MemoryMessageDatabase {
         match destination {
             Destination::User(userid) => {
                 let sender = message.sender.clone();
-                match self.direct_messages.entry((message.sender.clone(), userid.clone())) {
-                    Entry::Occupied(mut entry) => {
+                let room = match self.direct_messages.entry(make_user_pair(sender.clone(), userid.clone())) {
+                    Entry::Occupied(entry) => {
                         info!("adding message to storage using existing room");
-                        entry.get_mut().add_message(message)?
+                        entry.into_mut()
                     },
                     Entry::Vacant(entry) => {
                         info!("creating new message room with {:?} and {:?}", &sender, &userid);
                         entry.insert(MemoryMessageRoom::new(
                             vec![sender, userid].into_iter(),
                             true,
-                        ));
+                        ))
                     },
                 };
+                room.add_message(message)
+            }
+            Destination::Group(gc_id) => self.get_room_mut(&RoomId::Group(gc_id))?.add_message(message),
+        }
+    }
+
+    fn create_group(&mut self, id: GroupChatId, members: Vec<UserId>) -> Result<()> {
+        match self.group_messages.entry(id) {
+            Entry::Occupied(entry) => Err(MessageDAOError::GroupExists(entry.key().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(MemoryMessageRoom::new(members.into_iter(), false));
                 Ok(())
-            } // self.group_messages
+            }
         }
     }
 
@@ -103,4 +286,190 @@ impl MessagesDAO for MemoryMessageDatabase {
                 .ok_or(MessageDAOError::MissingRoomId(room_id.clone()))
         }
     }
-}
\ No newline at end of file
+
+    fn partners(&self, user: &UserId) -> HashSet<UserId> {
+        self.direct_messages
+            .values()
+            .chain(self.group_messages.values())
+            .map(|room| room.members())
+            .filter(|members| members.contains(user))
+            .flatten()
+            .filter(|member| *member != user)
+            .cloned()
+            .collect()
+    }
+}
+
+impl BacklogDAO for MemoryMessageDatabase {
+    fn push_backlog(&mut self, user: &UserId, packet: SPacket, limit: usize) -> Result<()> {
+        let (packets, dropped) = self.backlogs.entry(user.clone()).or_default();
+        packets.push_back(packet);
+        while packets.len() > limit {
+            packets.pop_front();
+            *dropped += 1;
+        }
+        Ok(())
+    }
+
+    fn take_backlog(&mut self, user: &UserId, expired_before: Timestamp) -> Result<Backlog> {
+        let (packets, dropped) = self.backlogs.remove(user).unwrap_or_default();
+        let (packets, expired): (Vec<SPacket>, Vec<SPacket>) = packets
+            .into_iter()
+            .partition(|p| p.time >= expired_before);
+        Ok(Backlog {
+            packets,
+            dropped: dropped + expired.len(),
+        })
+    }
+
+    fn purge_backlog(&mut self, user: &UserId, message: MessageId) -> Result<()> {
+        if let Some((packets, _)) = self.backlogs.get_mut(user) {
+            packets.retain(|p| !carries_content(&p.packet, message));
+        }
+        Ok(())
+    }
+}
+
+impl AccountDAO for MemoryMessageDatabase {
+    fn create_account(&mut self, user: &UserId, password_hash: String) -> Result<()> {
+        match self.accounts.entry(user.clone()) {
+            Entry::Occupied(_) => Err(MessageDAOError::UserExists(user.clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(password_hash);
+                Ok(())
+            }
+        }
+    }
+
+    fn password_hash(&self, user: &UserId) -> Result<Option<String>> {
+        Ok(self.accounts.get(user).cloned())
+    }
+
+    fn add_token(&mut self, token_hash: &str, user: &UserId, expires: Timestamp) -> Result<()> {
+        self.tokens.insert(token_hash.to_string(), (user.clone(), expires));
+        Ok(())
+    }
+
+    fn token_user(&mut self, token_hash: &str, now: Timestamp) -> Result<Option<UserId>> {
+        match self.tokens.get(token_hash) {
+            Some((user, expires)) if now < *expires => Ok(Some(user.clone())),
+            Some(_) => {
+                self.tokens.remove(token_hash);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn remove_token(&mut self, token_hash: &str) -> Result<()> {
+        self.tokens.remove(token_hash);
+        Ok(())
+    }
+}
+
+impl SettingsDAO for MemoryMessageDatabase {
+    fn draft_visibility(&self, user: &UserId, conversation: Option<&Destination>) -> Result<Option<DraftVisibility>> {
+        Ok(self.draft_visibility.get(&(user.clone(), conversation.cloned())).copied())
+    }
+
+    fn set_draft_visibility(
+        &mut self,
+        user: &UserId,
+        conversation: Option<&Destination>,
+        visibility: Option<DraftVisibility>,
+    ) -> Result<()> {
+        let key = (user.clone(), conversation.cloned());
+        match visibility {
+            Some(visibility) => self.draft_visibility.insert(key, visibility),
+            None => self.draft_visibility.remove(&key),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::identity::make_user_id;
+    use crate::protocol::Message;
+    use crate::storage::memory_storage::MemoryMessageRoom;
+    use crate::storage::query::{Cursor, MessageQuery, Page, TimeRange};
+    use crate::storage::MessageRoomDAO;
+    use uuid::Uuid;
+
+    fn room_with(messages: &[(&str, &str, u64)]) -> (MemoryMessageRoom, Vec<Uuid>) {
+        let members = vec![make_user_id("A".to_string()), make_user_id("B".to_string())];
+        let mut room = MemoryMessageRoom::new(members.into_iter(), true);
+        let mut ids = vec![];
+        for (sender, content, start_time) in messages {
+            let id = Uuid::new_v4();
+            ids.push(id);
+            room.add_message(Message {
+                sender: make_user_id(sender.to_string()),
+                content: content.to_string(),
+                id,
+                start_time: *start_time,
+                end_time: start_time + 5,
+                edited: None,
+                deleted: None,
+                reply_to: None,
+            }).unwrap();
+        }
+        (room, ids)
+    }
+
+    fn contents(messages: Vec<&Message>) -> Vec<&str> {
+        messages.into_iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn filters_in_message_order() {
+        // added out of order on purpose
+        let (mut room, ids) = room_with(&[("B", "second", 20), ("A", "first", 10), ("A", "third", 30)]);
+        let all = room.get_messages(&MessageQuery::default(), &Page::default()).unwrap();
+        assert_eq!(contents(all), vec!["first", "second", "third"]);
+
+        let from_a = MessageQuery { sender: Some(make_user_id("A".to_string())), ..Default::default() };
+        assert_eq!(contents(room.get_messages(&from_a, &Page::default()).unwrap()), vec!["first", "third"]);
+
+        let window = MessageQuery {
+            started: TimeRange { from: Some(15), until: Some(30) },
+            ..Default::default()
+        };
+        assert_eq!(contents(room.get_messages(&window, &Page::default()).unwrap()), vec!["second"]);
+
+        let has_ir = MessageQuery { contains: Some("ir".to_string()), ..Default::default() };
+        assert_eq!(contents(room.get_messages(&has_ir, &Page::default()).unwrap()), vec!["first", "third"]);
+
+        room.add_message(Message {
+            sender: make_user_id("B".to_string()),
+            content: "re: first".to_string(),
+            id: Uuid::new_v4(),
+            start_time: 40,
+            end_time: 45,
+            edited: None,
+            deleted: None,
+            reply_to: Some(ids[1]),
+        }).unwrap();
+        let replies = MessageQuery { reply_to: Some(ids[1]), ..Default::default() };
+        assert_eq!(contents(room.get_messages(&replies, &Page::default()).unwrap()), vec!["re: first"]);
+    }
+
+    #[test]
+    fn pages_from_cursors() {
+        let (room, ids) = room_with(&[("A", "1", 10), ("B", "2", 20), ("A", "3", 30), ("B", "4", 40)]);
+        let all = MessageQuery::default();
+        assert_eq!(contents(room.get_messages(&all, &Page::latest(2)).unwrap()), vec!["3", "4"]);
+
+        let older = Page { cursor: Cursor::Before(ids[2]), offset: 0, limit: Some(5) };
+        assert_eq!(contents(room.get_messages(&all, &older).unwrap()), vec!["1", "2"]);
+
+        let newer = Page { cursor: Cursor::After(ids[0]), offset: 1, limit: Some(1) };
+        assert_eq!(contents(room.get_messages(&all, &newer).unwrap()), vec!["3"]);
+
+        let only_b = |m: &Message| m.sender == make_user_id("B".to_string());
+        assert_eq!(contents(room.get_messages(&only_b, &Page::latest(1)).unwrap()), vec!["4"]);
+
+        let missing = Page { cursor: Cursor::Before(Uuid::new_v4()), offset: 0, limit: None };
+        assert!(room.get_messages(&all, &missing).is_err());
+    }
+}
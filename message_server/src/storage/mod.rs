@@ -1,10 +1,17 @@
-use crate::identity::{GroupChatId, UserId, UserPair};
-use crate::packet::Destination;
-use crate::protocol::{Message, MessageId};
+use std::collections::HashSet;
+use crate::identity::{make_user_pair, GroupChatId, UserId, UserPair};
+use crate::packet::{Destination, Packet, SPacket};
+use crate::protocol::{
+    DraftVisibility, Keystroke, Message, MessageId, MessageVersion, Receipt, ReceiptStatus, Revision, Timestamp,
+};
+use crate::storage::query::Page;
 pub mod memory_storage;
+pub mod query;
+pub mod sqlite_storage;
 
+/// Decides which messages a history query returns. See query::MessageQuery
 pub trait MessageFilter {
-    fn include_message(message: &Message) -> bool;
+    fn include_message(&self, message: &Message) -> bool;
 }
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
@@ -17,6 +24,17 @@ pub enum RoomId {
 pub enum MessageDAOError {
     MissingMessageId(MessageId),
     MissingRoomId(RoomId),
+    NotAMember(UserId),
+    /// Only group chats can gain or lose members
+    FixedMembership,
+    /// The message was deleted, so it can't be changed any more
+    MessageDeleted(MessageId),
+    GroupExists(GroupChatId),
+    /// Someone already registered this name
+    UserExists(UserId),
+    Sqlite(rusqlite::Error),
+    /// A stored packet couldn't be written or read back
+    Serde(serde_json::Error),
 }
 
 pub type Result<T> = std::result::Result<T, MessageDAOError>;
@@ -27,35 +45,143 @@ pub trait MessagesDAO {
 
     fn add_message(&mut self, message: Message, destination: Destination) -> Result<()>;
 
+    /// Start an empty group chat room
+    fn create_group(&mut self, id: GroupChatId, members: Vec<UserId>) -> Result<()>;
+
     fn get_room(&self, room_id: &RoomId) -> Result<&Self::RoomDAO>;
 
     fn get_room_mut(&mut self, room_id: &RoomId) -> Result<&mut Self::RoomDAO>;
+
+    /// Everyone other than `user` who shares a room with them
+    fn partners(&self, user: &UserId) -> HashSet<UserId>;
 }
 
 pub trait MessageRoomDAO {
-    fn new<M: Iterator<Item = UserId>>(members: M, is_dm: bool) -> Self;
-    fn get_messages<F: MessageFilter>(&self, filter: &F) -> Vec<&Message>;
+    /// Messages matching `filter`, in the room's order, sliced according to `page`.
+    /// Fails if the page's cursor names a message that isn't in this room.
+    fn get_messages<F: MessageFilter>(&self, filter: &F, page: &Page) -> Result<Vec<&Message>>;
 
     fn add_message(&mut self, message: Message) -> Result<()>;
 
-    fn get_message_mut(&mut self, m_id: MessageId) -> Option<&mut Message>;
-
     fn get_message(&self, m_id: MessageId) -> Option<&Message>;
 
-    fn edit_message(&mut self, m_id: MessageId, new_content: String) -> Result<()> {
-        self.get_message_mut(m_id)
-            .map(|m| m.content = new_content)
-            .ok_or(MessageDAOError::MissingMessageId(m_id))
+    /// Replace a message's content at `time`, keeping what it said before as an older version.
+    /// Returns the new version number (the original being 0).
+    /// Fails with MessageDeleted if the message was deleted
+    fn edit_message(&mut self, m_id: MessageId, new_content: String, time: Timestamp) -> Result<Revision>;
+
+    /// Every version of a message, oldest first, ending with its current content
+    fn get_versions(&self, m_id: MessageId) -> Result<Vec<MessageVersion>>;
+
+    fn members(&self) -> &HashSet<UserId>;
+
+    fn add_member(&mut self, uid: UserId) -> Result<()>;
+
+    fn remove_member(&mut self, uid: &UserId) -> Result<()>;
+
+    /// Remember how a message was typed, so it can be replayed
+    fn set_timeline(&mut self, m_id: MessageId, timeline: Vec<Keystroke>) -> Result<()>;
+
+    /// Every change made while a message was typed, oldest first.
+    /// Empty if none were recorded.
+    fn get_timeline(&self, m_id: MessageId) -> Result<Vec<Keystroke>>;
+
+    /// Delete a message at `time`, leaving a tombstone: it keeps its id and times (and so its place
+    /// in history) but loses its content, timeline and older versions. Fails with MessageDeleted if it already was.
+    fn remove_message(&mut self, m_id: MessageId, time: Timestamp) -> Result<()>;
+
+    /// Record that a message got as far as `status` with `member` at `time`. Statuses only move forward,
+    /// so this returns false (and changes nothing) if it had already got that far.
+    fn set_receipt(&mut self, m_id: MessageId, member: &UserId, status: ReceiptStatus, time: Timestamp) -> Result<bool>;
+
+    /// How far a message has got with each member it reached, ordered by member
+    fn get_receipts(&self, m_id: MessageId) -> Result<Vec<Receipt>>;
+}
+
+/// Packets that were waiting for a user, oldest first
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Backlog {
+    pub packets: Vec<SPacket>,
+    /// How many were thrown away (too old, or over the limit) since the backlog was last taken
+    pub dropped: usize,
+}
+
+/// Where packets wait for users who aren't connected
+pub trait BacklogDAO {
+    /// Queue `packet` for `user`. Once more than `limit` are waiting, the oldest are dropped.
+    fn push_backlog(&mut self, user: &UserId, packet: SPacket, limit: usize) -> Result<()>;
+
+    /// Remove everything queued for `user`. Packets from before `expired_before` are dropped.
+    fn take_backlog(&mut self, user: &UserId, expired_before: Timestamp) -> Result<Backlog>;
+
+    /// Throw away whatever queued for `user` has the content of `message` in it, once it's deleted.
+    /// These don't count as dropped.
+    fn purge_backlog(&mut self, user: &UserId, message: MessageId) -> Result<()>;
+}
+
+/// Whether a queued packet gives away what `message` said
+fn carries_content(packet: &Packet, message: MessageId) -> bool {
+    match packet {
+        Packet::NewMessage { uuid, .. } | Packet::Edit { uuid, .. } => *uuid == message,
+        _ => false,
     }
+}
+
+/// Where user accounts and their login tokens are kept
+pub trait AccountDAO {
+    /// Fails with UserExists if the name is taken
+    fn create_account(&mut self, user: &UserId, password_hash: String) -> Result<()>;
+
+    /// The stored hash for `user`, if they have an account
+    fn password_hash(&self, user: &UserId) -> Result<Option<String>>;
+
+    /// Remember a login token (only its hash is given) for `user` until `expires`
+    fn add_token(&mut self, token_hash: &str, user: &UserId, expires: Timestamp) -> Result<()>;
+
+    /// Who the token with this hash belongs to, if it exists and hasn't expired by `now`
+    fn token_user(&mut self, token_hash: &str, now: Timestamp) -> Result<Option<UserId>>;
+
+    fn remove_token(&mut self, token_hash: &str) -> Result<()>;
+}
 
-    // fn remove_message(&mut self, m_id: MessageId) -> Result<()>;
+/// Where users' preferences are kept
+pub trait SettingsDAO {
+    /// How `user` chose to show their drafts in the conversation with `conversation`,
+    /// or everywhere if that's None. None if they never chose.
+    fn draft_visibility(&self, user: &UserId, conversation: Option<&Destination>) -> Result<Option<DraftVisibility>>;
+
+    /// Passing None for `visibility` forgets the choice
+    fn set_draft_visibility(
+        &mut self,
+        user: &UserId,
+        conversation: Option<&Destination>,
+        visibility: Option<DraftVisibility>,
+    ) -> Result<()>;
 }
 
+/// Everything the message server keeps in storage
+pub trait Storage: MessagesDAO + BacklogDAO + AccountDAO + SettingsDAO {}
+
+impl<T: MessagesDAO + BacklogDAO + AccountDAO + SettingsDAO> Storage for T {}
+
 impl From<(UserId, Destination)> for RoomId {
     fn from(value: (UserId, Destination)) -> Self {
         let (user, dest) = value;
         match dest {
-            Destination::User(recipient) => RoomId::DM((user, recipient)),
+            Destination::User(recipient) => RoomId::DM(make_user_pair(user, recipient)),
+            Destination::Group(gc_id) => RoomId::Group(gc_id),
         }
     }
 }
+
+impl From<serde_json::Error> for MessageDAOError {
+    fn from(value: serde_json::Error) -> Self {
+        MessageDAOError::Serde(value)
+    }
+}
+
+impl From<rusqlite::Error> for MessageDAOError {
+    fn from(value: rusqlite::Error) -> Self {
+        MessageDAOError::Sqlite(value)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::identity::UserId;
+use crate::protocol::{Message, MessageId, Timestamp};
+use crate::storage::MessageFilter;
+
+/// Half-open range of timestamps: `from <= t < until`. Missing ends are unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub from: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+}
+
+/// The usual way to ask a room for its history.
+/// Every condition that is set must hold; the default query matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct MessageQuery {
+    pub sender: Option<UserId>,
+    pub started: TimeRange,
+    pub ended: TimeRange,
+    /// Case-sensitive substring of the content
+    pub contains: Option<String>,
+    /// Only replies to this message
+    pub reply_to: Option<MessageId>,
+}
+
+/// Where in the room's `message_order` a page starts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cursor {
+    /// Oldest messages first
+    #[default]
+    Start,
+    /// Newest messages first
+    End,
+    /// Messages strictly after this one, oldest first
+    After(MessageId),
+    /// Messages strictly before this one, newest first
+    Before(MessageId),
+}
+
+/// Which slice of the matching messages to return.
+/// Offset and limit count from the cursor in the direction it walks,
+/// but results always come back oldest to newest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Page {
+    pub cursor: Cursor,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: Timestamp) -> bool {
+        self.from.is_none_or(|from| from <= time) && self.until.is_none_or(|until| time < until)
+    }
+}
+
+impl MessageFilter for MessageQuery {
+    fn include_message(&self, message: &Message) -> bool {
+        self.sender.as_ref().is_none_or(|s| s == &message.sender)
+            && self.started.contains(message.start_time)
+            && self.ended.contains(message.end_time)
+            && self.contains.as_ref().is_none_or(|c| message.content.contains(c.as_str()))
+            && self.reply_to.is_none_or(|parent| message.reply_to == Some(parent))
+    }
+}
+
+impl<F: Fn(&Message) -> bool> MessageFilter for F {
+    fn include_message(&self, message: &Message) -> bool {
+        self(message)
+    }
+}
+
+impl Page {
+    /// The newest `limit` messages
+    pub fn latest(limit: usize) -> Page {
+        Page {
+            cursor: Cursor::End,
+            offset: 0,
+            limit: Some(limit),
+        }
+    }
+
+    /// Applies offset & limit to messages that are already filtered and
+    /// walking away from the cursor, then puts them back in chronological order.
+    pub fn collect<'a, I: Iterator<Item = &'a Message>>(&self, walk: I) -> Vec<&'a Message> {
+        let mut messages: Vec<&Message> = walk
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        if matches!(self.cursor, Cursor::End | Cursor::Before(_)) {
+            messages.reverse();
+        }
+        messages
+    }
+}
@@ -0,0 +1,1036 @@
+
+// implementation of message storage on an embedded SQLite file.
+// Rooms are loaded into memory when the database is opened, and every
+// change is written through to the file before it touches the cache.
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::identity::{make_user_id, make_user_pair, GroupChatId, UserId, UserPair};
+use crate::packet::{Destination, Packet, SPacket};
+use crate::protocol::{
+    DraftVisibility, EditOp, Keystroke, Message, MessageId, MessageVersion, Receipt, ReceiptStatus, Revision, Timestamp,
+};
+use crate::storage::memory_storage::MemoryMessageRoom;
+use crate::storage::query::Page;
+use crate::storage::{carries_content, AccountDAO, Backlog, BacklogDAO, MessageDAOError, MessageFilter, MessageRoomDAO, MessagesDAO, RoomId, SettingsDAO};
+use crate::storage::Result;
+
+/// Each entry upgrades the schema by one version (tracked in `PRAGMA user_version`).
+/// Never edit an entry that has shipped; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: rooms, their members and their messages
+    "CREATE TABLE rooms (
+        id INTEGER PRIMARY KEY,
+        is_dm INTEGER NOT NULL
+    );
+    CREATE TABLE room_members (
+        room_id INTEGER NOT NULL REFERENCES rooms(id),
+        user_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE messages (
+        id BLOB PRIMARY KEY,
+        room_id INTEGER NOT NULL REFERENCES rooms(id),
+        sender TEXT NOT NULL,
+        content TEXT NOT NULL,
+        start_time INTEGER NOT NULL,
+        end_time INTEGER NOT NULL
+    );
+    CREATE INDEX messages_by_time ON messages(room_id, start_time, end_time);",
+    // 2: group chats are found by their id rather than their members
+    "ALTER TABLE rooms ADD COLUMN group_id BLOB;
+    CREATE UNIQUE INDEX rooms_by_group ON rooms(group_id);",
+    // 3: dms used to be keyed by (sender, recipient), so A->B and B->A were separate rooms.
+    // Fold each pair into its oldest room, with members in canonical order.
+    "CREATE TEMP TABLE dm_pairs AS
+        SELECT room_id, MIN(user_id) AS lo, MAX(user_id) AS hi
+        FROM room_members
+        WHERE room_id IN (SELECT id FROM rooms WHERE is_dm)
+        GROUP BY room_id;
+    CREATE TEMP TABLE dm_merges AS
+        SELECT p.room_id AS old_id, (
+            SELECT MIN(q.room_id) FROM dm_pairs q WHERE q.lo = p.lo AND q.hi = p.hi
+        ) AS new_id
+        FROM dm_pairs p;
+    UPDATE messages SET room_id = (SELECT new_id FROM dm_merges WHERE old_id = messages.room_id)
+        WHERE room_id IN (SELECT old_id FROM dm_merges WHERE old_id != new_id);
+    DELETE FROM room_members WHERE room_id IN (SELECT old_id FROM dm_merges WHERE old_id != new_id);
+    DELETE FROM rooms WHERE id IN (SELECT old_id FROM dm_merges WHERE old_id != new_id);
+    UPDATE room_members SET position = (
+            SELECT room_members.user_id = hi AND lo != hi FROM dm_pairs WHERE dm_pairs.room_id = room_members.room_id
+        )
+        WHERE room_id IN (SELECT room_id FROM dm_pairs);
+    DROP TABLE dm_merges;
+    DROP TABLE dm_pairs;",
+    // 4: packets waiting for users who are offline, and how many of theirs were dropped
+    "CREATE TABLE backlog (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        dest_user TEXT,
+        dest_group BLOB,
+        time INTEGER NOT NULL,
+        packet TEXT NOT NULL
+    );
+    CREATE INDEX backlog_by_user ON backlog(user_id, id);
+    CREATE TABLE backlog_dropped (
+        user_id TEXT PRIMARY KEY,
+        count INTEGER NOT NULL
+    );",
+    // 5: accounts and the tokens they've logged in with
+    "CREATE TABLE accounts (
+        user_id TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE login_tokens (
+        token TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES accounts(user_id),
+        expires INTEGER NOT NULL
+    );",
+    // 6: every change made while typing a message, for replays
+    "CREATE TABLE keystrokes (
+        message_id BLOB NOT NULL REFERENCES messages(id),
+        position INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        op TEXT NOT NULL,
+        PRIMARY KEY (message_id, position)
+    );",
+    // 7: how users want their drafts shown, see conversation_key
+    "CREATE TABLE draft_visibility (
+        user_id TEXT NOT NULL,
+        conversation TEXT NOT NULL,
+        visibility TEXT NOT NULL,
+        PRIMARY KEY (user_id, conversation)
+    );",
+    // 8: deleted messages stay as tombstones
+    "ALTER TABLE messages ADD COLUMN deleted INTEGER;",
+    // 9: what edited messages said before
+    "ALTER TABLE messages ADD COLUMN edited INTEGER;
+    CREATE TABLE message_versions (
+        message_id BLOB NOT NULL REFERENCES messages(id),
+        version INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        PRIMARY KEY (message_id, version)
+    );",
+    // 10: how far each message got with each member of its room
+    "CREATE TABLE receipts (
+        message_id BLOB NOT NULL REFERENCES messages(id),
+        user_id TEXT NOT NULL,
+        status TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        PRIMARY KEY (message_id, user_id)
+    );",
+    // 11: replies point at the message they reply to
+    "ALTER TABLE messages ADD COLUMN reply_to BLOB;
+    CREATE INDEX messages_by_parent ON messages(reply_to);",
+    // 12: login tokens are only kept hashed. The ones kept before can't be hashed here, so those logins end
+    "DELETE FROM login_tokens;
+    ALTER TABLE login_tokens RENAME COLUMN token TO token_hash;",
+];
+
+type SharedConnection = Arc<Mutex<Connection>>;
+
+/// A room whose messages live in SQLite, with an in-memory copy for reads.
+pub struct SqliteMessageRoom {
+    conn: SharedConnection,
+    room_id: i64,
+    cache: MemoryMessageRoom,
+}
+
+/// Contains all messages, persisted to a SQLite database
+pub struct SqliteMessageDatabase {
+    conn: SharedConnection,
+    direct_messages: HashMap<UserPair, SqliteMessageRoom>,
+    group_messages: HashMap<GroupChatId, SqliteMessageRoom>,
+}
+
+impl MessageRoomDAO for SqliteMessageRoom {
+    fn get_messages<F: MessageFilter>(&self, filter: &F, page: &Page) -> Result<Vec<&Message>> {
+        self.cache.get_messages(filter, page)
+    }
+
+    fn add_message(&mut self, message: Message) -> Result<()> {
+        self.cache.check_member(&message.sender)?;
+        lock(&self.conn).execute(
+            "INSERT OR REPLACE INTO messages (id, room_id, sender, content, start_time, end_time, edited, deleted, reply_to)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                message.id,
+                self.room_id,
+                message.sender.to_string(),
+                message.content,
+                to_sql_time(message.start_time),
+                to_sql_time(message.end_time),
+                message.edited.map(to_sql_time),
+                message.deleted.map(to_sql_time),
+                message.reply_to,
+            ],
+        )?;
+        self.cache.add_message(message)
+    }
+
+    fn get_message(&self, m_id: MessageId) -> Option<&Message> {
+        self.cache.get_message(m_id)
+    }
+
+    // older versions aren't cached, only the current content
+    fn edit_message(&mut self, m_id: MessageId, new_content: String, time: Timestamp) -> Result<Revision> {
+        check_not_deleted(&self.cache, m_id)?;
+        let Some(message) = self.cache.get_message_mut(m_id) else {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        };
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        let older: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM message_versions WHERE message_id = ?1",
+            params![m_id],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "INSERT INTO message_versions (message_id, version, content, time) VALUES (?1, ?2, ?3, ?4)",
+            params![m_id, older, message.content, to_sql_time(message.edited.unwrap_or(message.end_time))],
+        )?;
+        tx.execute(
+            "UPDATE messages SET content = ?1, edited = ?2 WHERE id = ?3 AND room_id = ?4",
+            params![new_content, to_sql_time(time), m_id, self.room_id],
+        )?;
+        tx.commit()?;
+        message.content = new_content;
+        message.edited = Some(time);
+        Ok(older as Revision + 1)
+    }
+
+    fn get_versions(&self, m_id: MessageId) -> Result<Vec<MessageVersion>> {
+        check_not_deleted(&self.cache, m_id)?;
+        let db = lock(&self.conn);
+        let mut versions = db
+            .prepare("SELECT content, time FROM message_versions WHERE message_id = ?1 ORDER BY version")?
+            .query_map(params![m_id], |row| {
+                Ok(MessageVersion { content: row.get(0)?, time: from_sql_time(row.get(1)?) })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if let Some(message) = self.cache.get_message(m_id) {
+            versions.push(MessageVersion {
+                content: message.content.clone(),
+                time: message.edited.unwrap_or(message.end_time),
+            });
+        }
+        Ok(versions)
+    }
+
+    fn remove_message(&mut self, m_id: MessageId, time: Timestamp) -> Result<()> {
+        check_not_deleted(&self.cache, m_id)?;
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        tx.execute(
+            "UPDATE messages SET content = '', deleted = ?1 WHERE id = ?2 AND room_id = ?3",
+            params![to_sql_time(time), m_id, self.room_id],
+        )?;
+        tx.execute("DELETE FROM keystrokes WHERE message_id = ?1", params![m_id])?;
+        tx.execute("DELETE FROM message_versions WHERE message_id = ?1", params![m_id])?;
+        tx.commit()?;
+        drop(db);
+        self.cache.remove_message(m_id, time)
+    }
+
+    fn members(&self) -> &HashSet<UserId> {
+        self.cache.members()
+    }
+
+    fn add_member(&mut self, uid: UserId) -> Result<()> {
+        if self.cache.is_dm() {
+            return Err(MessageDAOError::FixedMembership);
+        }
+        lock(&self.conn).execute(
+            "INSERT OR IGNORE INTO room_members (room_id, user_id, position)
+             SELECT ?1, ?2, COUNT(*) FROM room_members WHERE room_id = ?1",
+            params![self.room_id, uid.to_string()],
+        )?;
+        self.cache.add_member(uid)
+    }
+
+    fn remove_member(&mut self, uid: &UserId) -> Result<()> {
+        if self.cache.is_dm() {
+            return Err(MessageDAOError::FixedMembership);
+        }
+        self.cache.check_member(uid)?;
+        lock(&self.conn).execute(
+            "DELETE FROM room_members WHERE room_id = ?1 AND user_id = ?2",
+            params![self.room_id, uid.to_string()],
+        )?;
+        self.cache.remove_member(uid)
+    }
+
+    // timelines aren't cached; they can be long and are only read for replays
+    fn set_timeline(&mut self, m_id: MessageId, timeline: Vec<Keystroke>) -> Result<()> {
+        if self.cache.get_message(m_id).is_none() {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM keystrokes WHERE message_id = ?1", params![m_id])?;
+        for (position, keystroke) in timeline.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO keystrokes (message_id, position, time, op) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    m_id,
+                    position as i64,
+                    to_sql_time(keystroke.time),
+                    serde_json::to_string(&keystroke.op)?,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_timeline(&self, m_id: MessageId) -> Result<Vec<Keystroke>> {
+        if self.cache.get_message(m_id).is_none() {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        let db = lock(&self.conn);
+        let rows = db
+            .prepare("SELECT time, op FROM keystrokes WHERE message_id = ?1 ORDER BY position")?
+            .query_map(params![m_id], |row| Ok((from_sql_time(row.get(0)?), row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(time, op)| Ok(Keystroke { time, op: serde_json::from_str::<EditOp>(&op)? }))
+            .collect()
+    }
+
+    // receipts aren't cached either
+    fn set_receipt(&mut self, m_id: MessageId, member: &UserId, status: ReceiptStatus, time: Timestamp) -> Result<bool> {
+        if self.cache.get_message(m_id).is_none() {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        self.cache.check_member(member)?;
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        let known: Option<String> = tx
+            .query_row(
+                "SELECT status FROM receipts WHERE message_id = ?1 AND user_id = ?2",
+                params![m_id, member.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let known = known.map(|known| serde_json::from_str::<ReceiptStatus>(&known)).transpose()?;
+        if known.is_some_and(|known| known >= status) {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO receipts (message_id, user_id, status, time) VALUES (?1, ?2, ?3, ?4)",
+            params![m_id, member.to_string(), serde_json::to_string(&status)?, to_sql_time(time)],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn get_receipts(&self, m_id: MessageId) -> Result<Vec<Receipt>> {
+        if self.cache.get_message(m_id).is_none() {
+            return Err(MessageDAOError::MissingMessageId(m_id));
+        }
+        let db = lock(&self.conn);
+        let rows = db
+            .prepare("SELECT user_id, status, time FROM receipts WHERE message_id = ?1 ORDER BY user_id")?
+            .query_map(params![m_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, from_sql_time(row.get(2)?)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(user, status, time)| {
+                Ok(Receipt { user: make_user_id(user), status: serde_json::from_str(&status)?, time })
+            })
+            .collect()
+    }
+}
+
+impl SqliteMessageDatabase {
+    /// Open (or create) the database file at `path` and load every room from it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteMessageDatabase> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// A database that only lives as long as this value. Mostly useful for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<SqliteMessageDatabase> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<SqliteMessageDatabase> {
+        migrate(&mut conn)?;
+        let conn = Arc::new(Mutex::new(conn));
+        let (direct_messages, group_messages) = load_rooms(&conn)?;
+        Ok(SqliteMessageDatabase {
+            conn,
+            direct_messages,
+            group_messages,
+        })
+    }
+}
+
+impl MessagesDAO for SqliteMessageDatabase {
+    type RoomDAO = SqliteMessageRoom;
+
+    fn add_message(&mut self, message: Message, destination: Destination) -> Result<()> {
+        info!("Adding message to sqlite db: {:?}", &message);
+        match destination {
+            Destination::User(userid) => {
+                let key = make_user_pair(message.sender.clone(), userid);
+                let room = match self.direct_messages.entry(key.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let (a, b) = key.users();
+                        info!("creating new message room with {:?} and {:?}", a, b);
+                        let room = MemoryMessageRoom::new(vec![a.clone(), b.clone()].into_iter(), true);
+                        // talking to yourself is a room with one member
+                        let members = if a == b { vec![a] } else { vec![a, b] };
+                        entry.insert(create_room(&self.conn, room, None, &members)?)
+                    }
+                };
+                room.add_message(message)
+            }
+            Destination::Group(gc_id) => self.get_room_mut(&RoomId::Group(gc_id))?.add_message(message),
+        }
+    }
+
+    fn create_group(&mut self, id: GroupChatId, members: Vec<UserId>) -> Result<()> {
+        if self.group_messages.contains_key(&id) {
+            return Err(MessageDAOError::GroupExists(id));
+        }
+        let room = MemoryMessageRoom::new(members.clone().into_iter(), false);
+        let members: Vec<&UserId> = members.iter().collect();
+        let room = create_room(&self.conn, room, Some(&id), &members)?;
+        self.group_messages.insert(id, room);
+        Ok(())
+    }
+
+    fn get_room(&self, room_id: &RoomId) -> Result<&SqliteMessageRoom> {
+        match room_id {
+            RoomId::DM(userpair) => self.direct_messages.get(userpair)
+                .ok_or(MessageDAOError::MissingRoomId(room_id.clone())),
+            RoomId::Group(gc_id) => self.group_messages.get(gc_id)
+                .ok_or(MessageDAOError::MissingRoomId(room_id.clone())),
+        }
+    }
+
+    fn get_room_mut(&mut self, room_id: &RoomId) -> Result<&mut SqliteMessageRoom> {
+        match room_id {
+            RoomId::DM(userpair) => self.direct_messages.get_mut(userpair)
+                .ok_or(MessageDAOError::MissingRoomId(room_id.clone())),
+            RoomId::Group(gc_id) => self.group_messages.get_mut(gc_id)
+                .ok_or(MessageDAOError::MissingRoomId(room_id.clone())),
+        }
+    }
+
+    fn partners(&self, user: &UserId) -> HashSet<UserId> {
+        self.direct_messages
+            .values()
+            .chain(self.group_messages.values())
+            .map(|room| room.members())
+            .filter(|members| members.contains(user))
+            .flatten()
+            .filter(|member| *member != user)
+            .cloned()
+            .collect()
+    }
+}
+
+// the backlog isn't cached; it's only read when someone connects
+impl BacklogDAO for SqliteMessageDatabase {
+    fn push_backlog(&mut self, user: &UserId, packet: SPacket, limit: usize) -> Result<()> {
+        let (dest_user, dest_group) = match &packet.destination {
+            Destination::User(uid) => (Some(uid.to_string()), None),
+            Destination::Group(gc_id) => (None, Some(gc_id.to_uuid())),
+        };
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        tx.execute(
+            "INSERT INTO backlog (user_id, sender, dest_user, dest_group, time, packet)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user.to_string(),
+                packet.sender.to_string(),
+                dest_user,
+                dest_group,
+                to_sql_time(packet.time),
+                serde_json::to_string(&packet.packet)?,
+            ],
+        )?;
+        let waiting: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM backlog WHERE user_id = ?1",
+            params![user.to_string()],
+            |row| row.get(0),
+        )?;
+        let over = waiting - limit as i64;
+        if over > 0 {
+            tx.execute(
+                "DELETE FROM backlog WHERE id IN (
+                    SELECT id FROM backlog WHERE user_id = ?1 ORDER BY id LIMIT ?2
+                )",
+                params![user.to_string(), over],
+            )?;
+            tx.execute(
+                "INSERT INTO backlog_dropped (user_id, count) VALUES (?1, ?2)
+                 ON CONFLICT(user_id) DO UPDATE SET count = count + excluded.count",
+                params![user.to_string(), over],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn take_backlog(&mut self, user: &UserId, expired_before: Timestamp) -> Result<Backlog> {
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        let rows = tx
+            .prepare(
+                "SELECT sender, dest_user, dest_group, time, packet FROM backlog
+                 WHERE user_id = ?1 ORDER BY id",
+            )?
+            .query_map(params![user.to_string()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<uuid::Uuid>>(2)?,
+                    from_sql_time(row.get(3)?),
+                    row.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut dropped = tx
+            .query_row(
+                "SELECT count FROM backlog_dropped WHERE user_id = ?1",
+                params![user.to_string()],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+            .unwrap_or(0) as usize;
+        tx.execute("DELETE FROM backlog WHERE user_id = ?1", params![user.to_string()])?;
+        tx.execute("DELETE FROM backlog_dropped WHERE user_id = ?1", params![user.to_string()])?;
+        tx.commit()?;
+
+        let mut packets = vec![];
+        for (sender, dest_user, dest_group, time, packet) in rows {
+            if time < expired_before {
+                dropped += 1;
+                continue;
+            }
+            let destination = match (dest_user, dest_group) {
+                (Some(uid), _) => Destination::User(make_user_id(uid)),
+                (None, Some(gc_id)) => Destination::Group(GroupChatId::from(gc_id)),
+                (None, None) => {
+                    warn!("Skipping backlog entry for {:?} with no destination", user);
+                    continue;
+                }
+            };
+            packets.push(SPacket {
+                sender: make_user_id(sender),
+                destination,
+                time,
+                packet: serde_json::from_str::<Packet>(&packet)?,
+            });
+        }
+        Ok(Backlog { packets, dropped })
+    }
+
+    fn purge_backlog(&mut self, user: &UserId, message: MessageId) -> Result<()> {
+        let mut db = lock(&self.conn);
+        let tx = db.transaction()?;
+        let rows = tx
+            .prepare("SELECT id, packet FROM backlog WHERE user_id = ?1")?
+            .query_map(params![user.to_string()], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (id, packet) in rows {
+            if carries_content(&serde_json::from_str::<Packet>(&packet)?, message) {
+                tx.execute("DELETE FROM backlog WHERE id = ?1", params![id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+// accounts aren't cached either; they're only needed when someone logs in or connects
+impl AccountDAO for SqliteMessageDatabase {
+    fn create_account(&mut self, user: &UserId, password_hash: String) -> Result<()> {
+        let inserted = lock(&self.conn).execute(
+            "INSERT OR IGNORE INTO accounts (user_id, password_hash) VALUES (?1, ?2)",
+            params![user.to_string(), password_hash],
+        )?;
+        if inserted == 0 {
+            return Err(MessageDAOError::UserExists(user.clone()));
+        }
+        Ok(())
+    }
+
+    fn password_hash(&self, user: &UserId) -> Result<Option<String>> {
+        Ok(lock(&self.conn)
+            .query_row(
+                "SELECT password_hash FROM accounts WHERE user_id = ?1",
+                params![user.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn add_token(&mut self, token_hash: &str, user: &UserId, expires: Timestamp) -> Result<()> {
+        lock(&self.conn).execute(
+            "INSERT INTO login_tokens (token_hash, user_id, expires) VALUES (?1, ?2, ?3)",
+            params![token_hash, user.to_string(), to_sql_time(expires)],
+        )?;
+        Ok(())
+    }
+
+    fn token_user(&mut self, token_hash: &str, now: Timestamp) -> Result<Option<UserId>> {
+        let db = lock(&self.conn);
+        db.execute(
+            "DELETE FROM login_tokens WHERE expires <= ?1",
+            params![to_sql_time(now)],
+        )?;
+        Ok(db
+            .query_row(
+                "SELECT user_id FROM login_tokens WHERE token_hash = ?1",
+                params![token_hash],
+                |row| row.get(0),
+            )
+            .optional()?
+            .map(make_user_id))
+    }
+
+    fn remove_token(&mut self, token_hash: &str) -> Result<()> {
+        lock(&self.conn).execute("DELETE FROM login_tokens WHERE token_hash = ?1", params![token_hash])?;
+        Ok(())
+    }
+}
+
+impl SettingsDAO for SqliteMessageDatabase {
+    fn draft_visibility(&self, user: &UserId, conversation: Option<&Destination>) -> Result<Option<DraftVisibility>> {
+        let stored: Option<String> = lock(&self.conn)
+            .query_row(
+                "SELECT visibility FROM draft_visibility WHERE user_id = ?1 AND conversation = ?2",
+                params![user.to_string(), conversation_key(conversation)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(stored.map(|v| serde_json::from_str(&v)).transpose()?)
+    }
+
+    fn set_draft_visibility(
+        &mut self,
+        user: &UserId,
+        conversation: Option<&Destination>,
+        visibility: Option<DraftVisibility>,
+    ) -> Result<()> {
+        let db = lock(&self.conn);
+        let key = conversation_key(conversation);
+        match visibility {
+            Some(visibility) => db.execute(
+                "INSERT OR REPLACE INTO draft_visibility (user_id, conversation, visibility) VALUES (?1, ?2, ?3)",
+                params![user.to_string(), key, serde_json::to_string(&visibility)?],
+            )?,
+            None => db.execute(
+                "DELETE FROM draft_visibility WHERE user_id = ?1 AND conversation = ?2",
+                params![user.to_string(), key],
+            )?,
+        };
+        Ok(())
+    }
+}
+
+/// How a per-conversation setting is keyed: `user:<name>` or `group:<id>`, or empty for everywhere
+fn conversation_key(conversation: Option<&Destination>) -> String {
+    match conversation {
+        None => String::new(),
+        Some(Destination::User(uid)) => format!("user:{}", uid),
+        Some(Destination::Group(gc_id)) => format!("group:{}", gc_id.to_uuid()),
+    }
+}
+
+fn check_not_deleted(room: &MemoryMessageRoom, m_id: MessageId) -> Result<()> {
+    match room.get_message(m_id) {
+        None => Err(MessageDAOError::MissingMessageId(m_id)),
+        Some(message) if message.deleted.is_some() => Err(MessageDAOError::MessageDeleted(m_id)),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Persist a brand-new room and hand back its handle.
+fn create_room(
+    conn: &SharedConnection,
+    room: MemoryMessageRoom,
+    group: Option<&GroupChatId>,
+    members: &[&UserId],
+) -> Result<SqliteMessageRoom> {
+    let mut db = lock(conn);
+    let tx = db.transaction()?;
+    tx.execute(
+        "INSERT INTO rooms (is_dm, group_id) VALUES (?1, ?2)",
+        params![room.is_dm(), group.map(GroupChatId::to_uuid)],
+    )?;
+    let room_id = tx.last_insert_rowid();
+    for (position, member) in members.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO room_members (room_id, user_id, position) VALUES (?1, ?2, ?3)",
+            params![room_id, member.to_string(), position as i64],
+        )?;
+    }
+    tx.commit()?;
+    Ok(SqliteMessageRoom {
+        conn: Arc::clone(conn),
+        room_id,
+        cache: room,
+    })
+}
+
+fn lock(conn: &SharedConnection) -> std::sync::MutexGuard<'_, Connection> {
+    // a panic mid-statement leaves nothing half-applied that sqlite won't roll back
+    conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn to_sql_time(time: Timestamp) -> i64 {
+    time as i64
+}
+
+fn from_sql_time(time: i64) -> Timestamp {
+    time as Timestamp
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating sqlite storage to version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+type Rooms = (HashMap<UserPair, SqliteMessageRoom>, HashMap<GroupChatId, SqliteMessageRoom>);
+
+fn load_rooms(conn: &SharedConnection) -> Result<Rooms> {
+    let db = lock(conn);
+    let mut dms = HashMap::new();
+    let mut groups = HashMap::new();
+    let mut room_stmt = db.prepare("SELECT id, is_dm, group_id FROM rooms")?;
+    let mut member_stmt = db.prepare(
+        "SELECT user_id FROM room_members WHERE room_id = ?1 ORDER BY position",
+    )?;
+    let mut message_stmt = db.prepare(
+        "SELECT id, sender, content, start_time, end_time, deleted, edited, reply_to FROM messages
+         WHERE room_id = ?1 ORDER BY start_time, end_time",
+    )?;
+    let room_rows = room_stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, bool>(1)?, row.get::<_, Option<uuid::Uuid>>(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (room_id, is_dm, group_id) in room_rows {
+        let members = member_stmt
+            .query_map(params![room_id], |row| row.get::<_, String>(0))?
+            .map(|m| m.map(make_user_id))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut cache = MemoryMessageRoom::new(members.clone().into_iter(), is_dm);
+        let messages = message_stmt.query_map(params![room_id], |row| {
+            Ok(Message {
+                id: row.get(0)?,
+                sender: make_user_id(row.get(1)?),
+                content: row.get(2)?,
+                start_time: from_sql_time(row.get(3)?),
+                end_time: from_sql_time(row.get(4)?),
+                deleted: row.get::<_, Option<i64>>(5)?.map(from_sql_time),
+                edited: row.get::<_, Option<i64>>(6)?.map(from_sql_time),
+                reply_to: row.get(7)?,
+            })
+        })?;
+        for message in messages {
+            cache.add_message(message?)?;
+        }
+        let room = SqliteMessageRoom {
+            conn: Arc::clone(conn),
+            room_id,
+            cache,
+        };
+        match (is_dm, group_id, members.as_slice()) {
+            (true, None, [a, b]) => {
+                dms.insert(make_user_pair(a.clone(), b.clone()), room);
+            }
+            (true, None, [a]) => {
+                dms.insert(make_user_pair(a.clone(), a.clone()), room);
+            }
+            (false, Some(group_id), _) => {
+                groups.insert(GroupChatId::from(group_id), room);
+            }
+            _ => warn!("Skipping sqlite room {} with unexpected members {:?}", room_id, members),
+        }
+    }
+    Ok((dms, groups))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::identity::{make_group_chat_id, make_user_id, make_user_pair};
+    use crate::packet::Destination;
+    use crate::protocol::{DraftVisibility, EditOp, Keystroke, Message, Receipt, ReceiptStatus};
+    use crate::storage::query::{MessageQuery, Page};
+    use crate::storage::sqlite_storage::{SqliteMessageDatabase, MIGRATIONS};
+    use rusqlite::{params, Connection};
+    use std::path::PathBuf;
+    use crate::packet::{Packet, SPacket};
+    use crate::storage::{AccountDAO, BacklogDAO, MessageRoomDAO, MessagesDAO, RoomId, SettingsDAO};
+    use uuid::Uuid;
+
+    /// A database file of its own for a test, deleted when the test is done with it (pass or fail)
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new() -> Self {
+            TempDb(std::env::temp_dir().join(format!("livetype-test-{}.sqlite", Uuid::new_v4())))
+        }
+
+        fn open(&self) -> SqliteMessageDatabase {
+            SqliteMessageDatabase::open(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn message(sender: &str, content: &str, start_time: u64) -> Message {
+        Message {
+            sender: make_user_id(sender.to_string()),
+            content: content.to_string(),
+            id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + 10,
+            edited: None,
+            deleted: None,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn messages_survive_reopening() {
+        let file = TempDb::new();
+        let a = make_user_id("A".to_string());
+        let b = make_user_id("B".to_string());
+        let first = message("A", "hello", 5);
+        let first_id = first.id;
+        let second = Message { reply_to: Some(first_id), ..message("A", "again", 20) };
+        let second_id = second.id;
+        {
+            let mut db = file.open();
+            db.add_message(first, Destination::User(b.clone())).unwrap();
+            db.add_message(second, Destination::User(b.clone())).unwrap();
+            let room = db.get_room_mut(&RoomId::DM(make_user_pair(a.clone(), b.clone()))).unwrap();
+            assert_eq!(room.edit_message(first_id, "hello!".to_string(), 40).unwrap(), 1);
+            room.edit_message(second_id, "again!".to_string(), 25).unwrap();
+            room.remove_message(second_id, 30).unwrap();
+            assert!(room.edit_message(second_id, "back".to_string(), 35).is_err());
+        }
+        let db = file.open();
+        let room = db.get_room(&RoomId::DM(make_user_pair(a, b))).unwrap();
+        assert_eq!(room.get_message(first_id).unwrap().content, "hello!");
+        assert_eq!(room.get_message(second_id).unwrap().reply_to, Some(first_id));
+        let versions = room.get_versions(first_id).unwrap();
+        let versions: Vec<(&str, u64)> = versions.iter().map(|v| (v.content.as_str(), v.time)).collect();
+        assert_eq!(versions, vec![("hello", 15), ("hello!", 40)]);
+        assert!(room.get_versions(second_id).is_err());
+        let tombstone = room.get_message(second_id).unwrap();
+        assert_eq!((tombstone.content.as_str(), tombstone.deleted, tombstone.start_time), ("", Some(30), 20));
+    }
+
+    #[test]
+    fn groups_keep_their_members() {
+        let file = TempDb::new();
+        let gc_id = make_group_chat_id();
+        let room_id = RoomId::Group(gc_id.clone());
+        let members = ["A", "B", "C"].map(|m| make_user_id(m.to_string()));
+        {
+            let mut db = file.open();
+            db.create_group(gc_id.clone(), members[..2].to_vec()).unwrap();
+            assert!(db.create_group(gc_id.clone(), vec![]).is_err());
+            let room = db.get_room_mut(&room_id).unwrap();
+            room.add_member(members[2].clone()).unwrap();
+            room.remove_member(&members[0]).unwrap();
+            db.add_message(message("C", "made it", 0), Destination::Group(gc_id.clone())).unwrap();
+            assert!(db.add_message(message("A", "left", 1), Destination::Group(gc_id.clone())).is_err());
+        }
+        let db = file.open();
+        let room = db.get_room(&room_id).unwrap();
+        assert_eq!(room.members(), &members[1..].iter().cloned().collect());
+        let everything = room.get_messages(&MessageQuery::default(), &Page::default()).unwrap();
+        assert_eq!(everything.len(), 1);
+    }
+
+    #[test]
+    fn old_dm_rooms_are_merged() {
+        // a database from before dms were canonical, with A->B and B->A in separate rooms
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..2] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 2).unwrap();
+        let (from_a, from_b) = (Uuid::new_v4(), Uuid::new_v4());
+        for (room, sender, recipient, m_id) in [(1, "A", "B", from_a), (2, "B", "A", from_b)] {
+            conn.execute("INSERT INTO rooms (id, is_dm) VALUES (?1, 1)", params![room]).unwrap();
+            for (position, member) in [sender, recipient].into_iter().enumerate() {
+                conn.execute(
+                    "INSERT INTO room_members (room_id, user_id, position) VALUES (?1, ?2, ?3)",
+                    params![room, member, position as i64],
+                ).unwrap();
+            }
+            conn.execute(
+                "INSERT INTO messages (id, room_id, sender, content, start_time, end_time)
+                 VALUES (?1, ?2, ?3, 'hi', ?2, ?2)",
+                params![m_id, room, sender],
+            ).unwrap();
+        }
+
+        let mut db = SqliteMessageDatabase::from_connection(conn).unwrap();
+        let (a, b) = (make_user_id("A".to_string()), make_user_id("B".to_string()));
+        let room = db.get_room(&RoomId::DM(make_user_pair(b.clone(), a.clone()))).unwrap();
+        assert!(room.get_message(from_a).is_some());
+        assert!(room.get_message(from_b).is_some());
+        // and new messages either way land in the same place
+        db.add_message(message("B", "still here", 9), Destination::User(a.clone())).unwrap();
+        let room = db.get_room(&RoomId::DM(make_user_pair(a, b))).unwrap();
+        assert_eq!(room.get_messages(&MessageQuery::default(), &Page::default()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn missing_rooms_and_messages() {
+        let mut db = SqliteMessageDatabase::open_in_memory().unwrap();
+        let a = make_user_id("A".to_string());
+        let b = make_user_id("B".to_string());
+        assert!(db.get_room(&RoomId::DM(make_user_pair(a.clone(), b.clone()))).is_err());
+        db.add_message(message("A", "hi", 0), Destination::User(b.clone())).unwrap();
+        let room = db.get_room_mut(&RoomId::DM(make_user_pair(a, b))).unwrap();
+        assert!(room.edit_message(Uuid::new_v4(), "nope".to_string(), 0).is_err());
+    }
+
+    #[test]
+    fn timelines_survive_reopening() {
+        let file = TempDb::new();
+        let (a, b) = (make_user_id("A".to_string()), make_user_id("B".to_string()));
+        let typed = message("A", "hi", 0);
+        let m_id = typed.id;
+        let timeline = vec![
+            Keystroke { time: 1, op: EditOp::Insert { offset: 0, text: "hu".to_string() } },
+            Keystroke { time: 4, op: EditOp::Delete { offset: 1, len: 1 } },
+            Keystroke { time: 9, op: EditOp::Insert { offset: 1, text: "i".to_string() } },
+        ];
+        {
+            let mut db = file.open();
+            db.add_message(typed, Destination::User(b.clone())).unwrap();
+            let room = db.get_room_mut(&RoomId::DM(make_user_pair(a.clone(), b.clone()))).unwrap();
+            room.set_timeline(m_id, timeline.clone()).unwrap();
+            assert!(room.set_timeline(Uuid::new_v4(), vec![]).is_err());
+        }
+        let db = file.open();
+        let room = db.get_room(&RoomId::DM(make_user_pair(a, b))).unwrap();
+        assert_eq!(room.get_timeline(m_id).unwrap(), timeline);
+    }
+
+    #[test]
+    fn receipts_survive_reopening() {
+        let file = TempDb::new();
+        let (a, b) = (make_user_id("A".to_string()), make_user_id("B".to_string()));
+        let sent = message("A", "hi", 0);
+        let m_id = sent.id;
+        {
+            let mut db = file.open();
+            db.add_message(sent, Destination::User(b.clone())).unwrap();
+            let room = db.get_room_mut(&RoomId::DM(make_user_pair(a.clone(), b.clone()))).unwrap();
+            assert!(room.set_receipt(m_id, &b, ReceiptStatus::Delivered, 1).unwrap());
+            assert!(room.set_receipt(m_id, &b, ReceiptStatus::Read, 2).unwrap());
+            // never backwards
+            assert!(!room.set_receipt(m_id, &b, ReceiptStatus::Delivered, 3).unwrap());
+            assert!(room.set_receipt(m_id, &make_user_id("C".to_string()), ReceiptStatus::Read, 4).is_err());
+        }
+        let db = file.open();
+        let room = db.get_room(&RoomId::DM(make_user_pair(a, b.clone()))).unwrap();
+        assert_eq!(room.get_receipts(m_id).unwrap(), vec![Receipt { user: b, status: ReceiptStatus::Read, time: 2 }]);
+    }
+
+    #[test]
+    fn backlog_survives_reopening() {
+        let file = TempDb::new();
+        let (a, b) = (make_user_id("A".to_string()), make_user_id("B".to_string()));
+        let gc_id = make_group_chat_id();
+        let queued = |time, destination| SPacket {
+            sender: a.clone(),
+            destination,
+            time,
+            packet: Packet::GroupInfo { members: vec!["A".to_string()] },
+        };
+        {
+            let mut db = file.open();
+            db.push_backlog(&b, queued(1, Destination::User(b.clone())), 2).unwrap();
+            db.push_backlog(&b, queued(20, Destination::Group(gc_id.clone())), 2).unwrap();
+            db.push_backlog(&b, queued(30, Destination::User(b.clone())), 2).unwrap();
+            db.push_backlog(&a, queued(40, Destination::User(a.clone())), 2).unwrap();
+        }
+        let mut db = file.open();
+        let backlog = db.take_backlog(&b, 25).unwrap();
+        // one over the limit, one too old
+        assert_eq!(backlog.dropped, 2);
+        assert_eq!(backlog.packets, vec![queued(30, Destination::User(b.clone()))]);
+        db.push_backlog(&b, queued(50, Destination::Group(gc_id.clone())), 2).unwrap();
+        assert_eq!(db.take_backlog(&b, 0).unwrap().packets, vec![queued(50, Destination::Group(gc_id))]);
+        assert_eq!(db.take_backlog(&a, 0).unwrap().packets.len(), 1);
+
+        // a deleted message's content leaves the backlog, without counting as dropped
+        let m_id = Uuid::new_v4();
+        let said = |packet| SPacket { packet, ..queued(60, Destination::User(b.clone())) };
+        let content = "oops".to_string();
+        db.push_backlog(&b, said(Packet::NewMessage { uuid: m_id, content, start_time: 60, end_time: 60, reply_to: None }), 5).unwrap();
+        db.push_backlog(&b, said(Packet::DeleteMessage { uuid: m_id }), 5).unwrap();
+        db.purge_backlog(&b, m_id).unwrap();
+        let backlog = db.take_backlog(&b, 0).unwrap();
+        assert_eq!((backlog.packets, backlog.dropped), (vec![said(Packet::DeleteMessage { uuid: m_id })], 0));
+    }
+
+    #[test]
+    fn accounts_and_tokens() {
+        let mut db = SqliteMessageDatabase::open_in_memory().unwrap();
+        let a = make_user_id("A".to_string());
+        db.create_account(&a, "hash".to_string()).unwrap();
+        assert!(db.create_account(&a, "other".to_string()).is_err());
+        assert_eq!(db.password_hash(&a).unwrap().as_deref(), Some("hash"));
+        assert_eq!(db.password_hash(&make_user_id("B".to_string())).unwrap(), None);
+
+        db.add_token("t", &a, 100).unwrap();
+        assert_eq!(db.token_user("t", 50).unwrap(), Some(a.clone()));
+        assert_eq!(db.token_user("t", 100).unwrap(), None);
+        db.add_token("u", &a, 100).unwrap();
+        db.remove_token("u").unwrap();
+        assert_eq!(db.token_user("u", 0).unwrap(), None);
+    }
+
+    #[test]
+    fn draft_visibility_settings() {
+        let mut db = SqliteMessageDatabase::open_in_memory().unwrap();
+        let (a, b) = (make_user_id("A".to_string()), make_user_id("B".to_string()));
+        let with_b = Destination::User(b.clone());
+        let group = Destination::Group(make_group_chat_id());
+        db.set_draft_visibility(&a, None, Some(DraftVisibility::Typing)).unwrap();
+        db.set_draft_visibility(&a, Some(&with_b), Some(DraftVisibility::Hidden)).unwrap();
+        assert_eq!(db.draft_visibility(&a, None).unwrap(), Some(DraftVisibility::Typing));
+        assert_eq!(db.draft_visibility(&a, Some(&with_b)).unwrap(), Some(DraftVisibility::Hidden));
+        assert_eq!(db.draft_visibility(&a, Some(&group)).unwrap(), None);
+        assert_eq!(db.draft_visibility(&b, None).unwrap(), None);
+
+        db.set_draft_visibility(&a, Some(&with_b), None).unwrap();
+        assert_eq!(db.draft_visibility(&a, Some(&with_b)).unwrap(), None);
+    }
+}
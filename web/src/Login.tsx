@@ -1,5 +1,5 @@
 import React, { useEffect, useReducer, useRef, useState } from 'react';
-import { WebPacket, Message, Draft, UserId, assertUserId, assertUuid, uuid2str, str2uuid, Base64Uuid, WebDest } from './protocol';
+import { WebPacket, Message, Draft, UserId, Uuid, assertUserId, assertUuid, uuid2str, str2uuid, Base64Uuid, WebDest, diffOps, applyOp } from './protocol';
 import Messages from './Messages';
 import DraftMessage from './DraftMessage';
 
@@ -37,9 +37,13 @@ const initialState: State = {
   senderDrafts: new Map<UserId, Draft>(),
 }
 
+// how many old messages to load when opening a conversation
+const HISTORY_PAGE = 50;
+
 const ACTIONS = {
   UPDATE_USERNAME: 'UPDATE_USERNAME',
   ADD_MESSAGE: 'ADD_MESSAGE',
+  ADD_HISTORY: 'ADD_HISTORY',
   UPDATE_CURRENT_DRAFT: 'UPDATE_CURRENT_DRAFT',
   UPDATE_SENDER_DRAFTS: 'UPDATE_SENDER_DRAFTS',
   UPDATE_MESSAGE: 'UPDATE_MESSAGE',
@@ -52,6 +56,9 @@ const ACTIONS = {
 
 const Login: React.FC = () => {
   const [username, setUsername] = useState('');
+  const [password, setPassword] = useState('');
+  const [token, setToken] = useState<string | null>(null);
+  const [authError, setAuthError] = useState<string | null>(null);
   const [submitted, setSubmitted] = useState(false);
   const [triedLogin, setTriedLogin] = useState(false);
   const [isConnected, setIsConnected] = useState(false);
@@ -83,6 +90,13 @@ const Login: React.FC = () => {
           ...state,
           messages: [...state.messages, payload]
         };
+      case ACTIONS.ADD_HISTORY:
+        // skip anything we already have, e.g. when asking twice
+        const known = new Set(state.messages.map(m => m.uuid));
+        return {
+          ...state,
+          messages: [...payload.filter((m: Message) => !known.has(m.uuid)), ...state.messages]
+        };
       case ACTIONS.UPDATE_MESSAGE:
         return {
           ...state,
@@ -189,6 +203,7 @@ const Login: React.FC = () => {
         end_time: present(wpacket.timestamp),
       };
       dispatch({ type: ACTIONS.ADD_MESSAGE, payload: newMessage });
+      markRead(wpacket, packet.NewMessage.uuid);
     } else if (packet.StartDraft) {
       console.log("Received a StartDraft packet...doing nothing");
     } else if (packet.NewDraft) {
@@ -198,9 +213,11 @@ const Login: React.FC = () => {
         console.log('updating current draft uuid');
         dispatch({
           type: ACTIONS.UPDATE_CURRENT_DRAFT,
-          payload: (state: State) => ({
+          // the server's copy starts empty; the next change sends what was typed so far
+          payload: (_state: State) => ({
             uuid: uuid2str(present(packet.NewDraft?.uuid)),
-            content: state.currentDraft?.content,
+            content: '',
+            revision: 0,
             start_time: present(packet.NewDraft?.start_time),
             end_time: undefined,
           })
@@ -237,12 +254,41 @@ const Login: React.FC = () => {
           return senderDrafts;
         }
       });
+    } else if (packet.Delta) {
+      const delta = packet.Delta;
+      const uuid = uuid2str(delta.uuid);
+      dispatch({
+        type: ACTIONS.UPDATE_SENDER_DRAFTS,
+        payload: (state: State) => {
+          let senderDrafts = new Map(state.senderDrafts);
+          for (let [sender, draft] of [...senderDrafts.entries()]) {
+            // out of step drafts wait for the next full Edit
+            if (draft.uuid === uuid && (draft.revision ?? 0) === delta.revision) {
+              senderDrafts.set(sender, {
+                ...draft,
+                content: applyOp(draft.content, delta.op),
+                revision: delta.revision + 1,
+              });
+            }
+          }
+          return senderDrafts;
+        }
+      });
     } else if (packet.Edit) {
       // works on any message with the correct uuid
       console.log('receieved edit', packet.Edit);
       let uuid = uuid2str(present(packet.Edit?.uuid));
       let content = present(packet.Edit?.content);
-      if (packet.Edit?.editing_draft) {
+      let revision = packet.Edit?.revision;
+      if (packet.Edit?.editing_draft && wpacket.sender === username) {
+        // our own draft, put back in step by the server
+        dispatch({
+          type: ACTIONS.UPDATE_CURRENT_DRAFT,
+          payload: (state: State) => state.currentDraft.uuid === uuid
+            ? { ...state.currentDraft, content, revision }
+            : state.currentDraft
+        });
+      } else if (packet.Edit?.editing_draft) {
         dispatch({
           type: ACTIONS.UPDATE_SENDER_DRAFTS,
           payload: (state: State) => {
@@ -252,7 +298,8 @@ const Login: React.FC = () => {
               if (draft.uuid === uuid) {
                 senderDrafts.set(sender, {
                   ...draft,
-                  content
+                  content,
+                  revision,
                 });
                 editedDraft = true;
                 break;
@@ -262,6 +309,7 @@ const Login: React.FC = () => {
               senderDrafts.set(assertUserId(wpacket.sender), {
                 uuid: uuid,
                 content: content,
+                revision,
                 start_time: present(wpacket.timestamp),
                 end_time: undefined,
               });
@@ -275,6 +323,29 @@ const Login: React.FC = () => {
           payload: { uuid, content }
         });
       }
+    } else if (packet.Error) {
+      console.warn("The server refused a request", packet.Error);
+    } else if (packet.Receipt) {
+      console.log(`${wpacket.sender} has ${packet.Receipt.status === 'Read' ? 'read' : 'received'} a message`, packet.Receipt);
+    } else if (packet.Presence) {
+      console.log(`${wpacket.sender} is ${packet.Presence.status.toLowerCase()}`, packet.Presence);
+    } else if (packet.DeleteMessage) {
+      // the message keeps its place, without its content
+      dispatch({
+        type: ACTIONS.UPDATE_MESSAGE,
+        payload: { uuid: uuid2str(packet.DeleteMessage.uuid), content: '' }
+      });
+    } else if (packet.SyncMessages) {
+      console.log("received history", packet.SyncMessages);
+      const history: Message[] = packet.SyncMessages.messages.map(record => ({
+        sender: record.sender,
+        destination: { User: record.sender === username ? assertUserId(wpacket.sender) : username },
+        uuid: uuid2str(record.uuid),
+        content: record.content,
+        start_time: record.start_time,
+        end_time: record.end_time,
+      }));
+      dispatch({ type: ACTIONS.ADD_HISTORY, payload: history });
     } else if (packet.EndDraft) {
       console.log("received end draft", packet.EndDraft);
       dispatch({
@@ -284,26 +355,45 @@ const Login: React.FC = () => {
           packet: wpacket
         }
       })
+      markRead(wpacket, packet.EndDraft.uuid);
+    }
+  }
+
+  // finished messages show up as soon as they arrive, so they count as read
+  const markRead = (wpacket: WebPacket, uuid: Uuid) => {
+    if (wpacket.sender === username) {
+      return;
     }
+    sendWebPacket({
+      destination: wpacket.destination.Group ? wpacket.destination : { User: assertUserId(wpacket.sender) },
+      content: { MarkRead: { uuid } },
+    });
   }
 
   useEffect(() => {
-    if (submitted && !isConnected && (!wsRef.current || wsRef.current.readyState === WebSocket.CLOSED)) {
-      const WS_URL = `ws://localhost:8000/updates/${username}`;
+    if (submitted && token && !isConnected && (!wsRef.current || wsRef.current.readyState === WebSocket.CLOSED)) {
+      const WS_URL = `ws://localhost:8000/updates?token=${token}`;
       const ws = new WebSocket(WS_URL);
       console.log(ws)
       wsRef.current = ws;
       ws.onmessage = (event: MessageEvent) => {
-        let webpacket = JSON.parse(event.data);
+        let webpacket: WebPacket = JSON.parse(event.data);
         processPacket(webpacket);
+        if (webpacket.seq !== undefined) {
+          ws.send(JSON.stringify({
+            destination: { User: username },
+            content: { Ack: { seq: webpacket.seq } }
+          }));
+        }
       }
       ws.onopen = () => {
         setIsConnected(true);
         console.log('✅ Connected to server');
       }
-      ws.onclose = () => {
+      ws.onclose = (event: CloseEvent) => {
         setIsConnected(false);
-        console.log('❌ Disconnected from server');
+        // the server says why when it hangs up, e.g. when it's shutting down
+        console.log('❌ Disconnected from server', event.reason);
         setSubmitted(false);
         dispatch({ type: ACTIONS.RESET, payload: null });
       }
@@ -318,12 +408,63 @@ const Login: React.FC = () => {
         wsRef.current?.close();
       }
     };
-  }, [submitted, username]);
+  }, [submitted, username, token]);
+
+  // let the people we talk to know when this window isn't being looked at
+  useEffect(() => {
+    if (!isConnected) {
+      return;
+    }
+    const onVisibilityChange = () => sendWebPacket({
+      destination: { User: assertUserId(username) },
+      content: { Heartbeat: { idle: document.hidden } },
+    });
+    document.addEventListener('visibilitychange', onVisibilityChange);
+    return () => document.removeEventListener('visibilitychange', onVisibilityChange);
+  }, [isConnected, username]);
+
+  const postCredentials = (path: string) => fetch(`http://localhost:8000${path}`, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ username, password }),
+  });
+
+  // log in (making the account first if asked to), then connect with the token we get back
+  const logIn = async (createAccount: boolean) => {
+    if (!username || !password) {
+      return;
+    }
+    if (createAccount) {
+      const created = await postCredentials('/register');
+      if (!created.ok) {
+        setAuthError(created.status === 409 ? 'That name is taken' : 'Unable to create account');
+        return;
+      }
+    }
+    const response = await postCredentials('/login');
+    if (!response.ok) {
+      setAuthError('Wrong username or password');
+      return;
+    }
+    setAuthError(null);
+    setToken((await response.json()).token);
+    setSubmitted(true);
+  };
 
   const sendWebPacket = (wpacket: WebPacket) => {
     wsRef.current?.send(JSON.stringify(wpacket));
   }
 
+  const requestHistory = (other: UserId) => {
+    if (!other) {
+      return;
+    }
+    sendWebPacket({
+      destination: { User: other },
+      content: { SyncRequest: { limit: HISTORY_PAGE } }
+    });
+  };
+
   const getRecipient = () => {
     return present(sendFieldRef.current?.value);
   };
@@ -343,22 +484,25 @@ const Login: React.FC = () => {
     if (state.drafting) {
       if (state.currentDraft.uuid) {
         console.log("updating own draft");
-        // edit this draft
-        const packet: WebPacket = {
-          destination: { User: getRecipient() },
-          content: {
-            Edit: {
-              uuid: str2uuid(present(state.currentDraft.uuid)),
-              content: inputContent,
-              editing_draft: true,
+        // send just what changed since the last revision
+        let revision = state.currentDraft.revision ?? 0;
+        for (const op of diffOps(state.currentDraft.content, inputContent)) {
+          sendWebPacket({
+            destination: { User: getRecipient() },
+            content: {
+              Delta: {
+                uuid: str2uuid(present(state.currentDraft.uuid)),
+                revision,
+                op,
+              }
             }
-          }
+          });
+          revision++;
         }
-        sendWebPacket(packet);
         dispatch({
           type: ACTIONS.UPDATE_CURRENT_DRAFT, 
           payload: (pstate: State) => (
-            { ...pstate.currentDraft, content: inputContent }
+            { ...pstate.currentDraft, content: inputContent, revision }
           )
         });
       }
@@ -370,7 +514,7 @@ const Login: React.FC = () => {
       const packet: WebPacket = {
         destination: { User: getRecipient() },
         content: {
-          StartDraft: null
+          StartDraft: {}
         }
       }
       dispatch({type: ACTIONS.UPDATE_CURRENT_DRAFT, payload: (pdraft: State) => ({
@@ -408,7 +552,7 @@ const Login: React.FC = () => {
         Message room for {username}
         <br />
         <div>
-          Send to: <input type="text" id='send_to' ref={sendFieldRef}/>
+          Send to: <input type="text" id='send_to' ref={sendFieldRef} onBlur={(e) => requestHistory(e.target.value)}/>
           <Messages messages={state.messages} username={state.username} drafts={state.senderDrafts} />
           Message: <input type="text" id='message_content' ref={inputRef} onInput={() => { handleTypedDraft(getTextboxContent()); }} />
           <button onClick={
@@ -428,13 +572,21 @@ const Login: React.FC = () => {
           <p> 🔴 </p>
           <h2>Login</h2>
           {triedLogin ? <p>⚠️ Failed to connect. Retry </p> : null}
+          {authError ? <p>⚠️ {authError}</p> : null}
           <input
             type="text"
             placeholder="Enter your username"
             value={username}
             onChange={(e) => setUsername(e.target.value)}
           />
-          <button onClick={() => {if (username) { setSubmitted(true); }}}>Submit</button>
+          <input
+            type="password"
+            placeholder="Enter your password"
+            value={password}
+            onChange={(e) => setPassword(e.target.value)}
+          />
+          <button onClick={() => logIn(false)}>Log in</button>
+          <button onClick={() => logIn(true)}>Create account</button>
         </div>
       )}
     </div>
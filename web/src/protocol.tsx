@@ -8,24 +8,37 @@ pub struct WebPacket {
     destination: WebDest,
     sender: Option<String>, // only used going toward client
     timestamp: Option<Timestamp>, // only used going toward client
+    seq: Option<Seq>, // only used going toward client
+    request_id: Option<RequestId>, // only used going toward server
 }
 enum WebDest {
     User(String),
-    // Group(Uuid) // sometime later for group chats
+    Group(#[serde(with = "uuid::serde::compact")] Uuid),
 }
 pub enum Packet {
     ///
-    NewMessage { content: String },
+    NewMessage {
+        content: String,
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
+    },
     // SyncMessage(Uuid, String), // to be used to sync database w/ chats
     /// A user only has one draft at a time in a conversation - the last thing they typed
-    StartDraft,
+    StartDraft {
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
+    },
     NewDraft {
         uuid: Uuid,
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
     },
     EndDraft {
         #[serde(with = "uuid::serde::compact")]
         uuid: Uuid,
         content: String,
+        #[serde(default, with = "compact_option")]
+        reply_to: Option<MessageId>,
     },
     DiscardDraft {
         #[serde(with = "uuid::serde::compact")]
@@ -36,13 +49,194 @@ pub enum Packet {
         uuid: Uuid,
         content: String,
         editing_draft: bool,
+        #[serde(default)]
+        revision: Revision,
+    },
+    Delta {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        revision: Revision,
+        op: EditOp,
+    },
+    SyncRequest {
+        before: Option<Uuid>,
+        limit: usize,
+    },
+    SyncMessages {
+        messages: Vec<MessageRecord>,
+        more: bool,
+    },
+    CreateGroup {
+        members: Vec<String>,
+    },
+    AddMember {
+        user: String,
+    },
+    LeaveGroup,
+    GroupInfo {
+        members: Vec<String>,
+    },
+    Ack {
+        seq: Seq,
+    },
+    Missed {
+        count: usize,
+    },
+    Welcome {
+        #[serde(with = "uuid::serde::compact")]
+        session: Uuid,
+        resumed: bool,
+    },
+    EditHistoryRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    EditHistory {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        versions: Vec<MessageVersion>,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(default, with = "compact_option")]
+        uuid: Option<MessageId>,
+        #[serde(default)]
+        request_id: Option<RequestId>,
+    },
+    Accepted {
+        request_id: RequestId,
+    },
+    DeleteMessage {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    SetDraftVisibility {
+        visibility: Option<DraftVisibility>,
+        #[serde(default)]
+        everywhere: bool,
     },
+    ReplayRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        #[serde(default = "full_speed")]
+        speed: u32, // percent
+    },
+    Replay {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        time: Timestamp,
+        op: EditOp,
+        last: bool,
+    },
+    MarkRead {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    Receipt {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        status: ReceiptStatus,
+        time: Timestamp,
+    },
+    ReceiptsRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    Receipts {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        receipts: Vec<ReceiptRecord>,
+    },
+    RepliesRequest {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+    },
+    Replies {
+        #[serde(with = "uuid::serde::compact")]
+        uuid: MessageId,
+        messages: Vec<MessageRecord>,
+    },
+    Heartbeat {
+        idle: bool,
+    },
+    SubscribePresence {
+        users: Vec<String>,
+    },
+    UnsubscribePresence {
+        users: Vec<String>,
+    },
+    Presence {
+        status: PresenceStatus,
+        last_seen: Option<Timestamp>,
+    },
+}
+pub enum ErrorCode {
+    BadPacket,
+    ServerOnly,
+    MissingDraft,
+    NotDraftOwner,
+    BadEndDraft,
+    MissingMessage,
+    MissingConversation,
+    NotAuthor,
+    MessageDeleted,
+    NotAMember,
+    NotAGroup,
+    StaleRevision,
+    OutOfBounds,
+    TooManyReplays,
+    Internal,
+}
+pub enum DraftVisibility {
+    Live,
+    Typing,
+    Hidden,
+}
+// offsets & lengths count unicode characters (code points)
+pub enum EditOp {
+    Insert { offset: usize, text: String },
+    Delete { offset: usize, len: usize },
+}
+pub struct MessageRecord {
+    #[serde(with = "uuid::serde::compact")]
+    pub uuid: MessageId,
+    pub sender: String,
+    pub content: String,
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    #[serde(default)]
+    pub edited: Option<Timestamp>,
+    #[serde(default)]
+    pub deleted: Option<Timestamp>,
+    #[serde(default, with = "compact_option")]
+    pub reply_to: Option<MessageId>,
+}
+pub struct MessageVersion {
+    pub content: String,
+    pub time: Timestamp,
+}
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+pub struct ReceiptRecord {
+    pub user: String,
+    pub status: ReceiptStatus,
+    pub time: Timestamp,
+}
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Offline,
 }
 */
 
 type Uuid = Array<number>;
 type Base64Uuid = string;
 type Timestamp = number;
+type Seq = number;
+type RequestId = number;
+type Revision = number;
 type UserId = string;
 
 interface WebPacket {
@@ -50,28 +244,39 @@ interface WebPacket {
   destination: WebDest,
   sender?: UserId,
   timestamp?: Timestamp,
+  // may skip numbers when a newer Edit of a draft replaced older ones still waiting to be sent
+  seq?: Seq,
+  // pick one to hear back about this packet in Accepted or Error
+  request_id?: RequestId,
 }
 
 interface WebDest {
-  User: UserId
-  // Group: Uuid // sometime later for group chats
+  // exactly one of these
+  User?: UserId,
+  Group?: Uuid,
 }
 
 interface Packet {
   // all of the variants are optional, but at least one should be present
   NewMessage?: {
     uuid: Uuid,
-    content: string
+    content: string,
+    reply_to?: Uuid | null,
+  },
+  // reply_to makes it a reply to an earlier message of the same conversation
+  StartDraft?: {
+    reply_to?: Uuid | null,
   },
-  // SyncMessage: Uuid, String // to be used to sync database w/ chats
-  StartDraft?: null,
   NewDraft?: {
     uuid: Uuid,
-    start_time: Timestamp
+    start_time: Timestamp,
+    reply_to?: Uuid | null,
   },
+  // from us, reply_to (if set) replaces what the draft was replying to
   EndDraft?: {
     uuid: Uuid,
     content: string,
+    reply_to?: Uuid | null,
   },
   DiscardDraft?: {
     uuid: Uuid
@@ -80,7 +285,185 @@ interface Packet {
     uuid: Uuid,
     content: string,
     editing_draft: boolean,
-  }
+    // for drafts, the revision this edit made; for sent messages, the version (the original is 0)
+    revision?: Revision,
+  },
+  // one change to a draft, made against `revision`. The server may hold changes back when a draft
+  // changes faster than its update rate, then send the newest content as a full Edit
+  Delta?: {
+    uuid: Uuid,
+    revision: Revision,
+    op: EditOp,
+  },
+  SyncRequest?: {
+    before?: Uuid,
+    limit: number,
+  },
+  SyncMessages?: {
+    messages: MessageRecord[],
+    more: boolean,
+  },
+  // send to yourself; the new group's id comes back in GroupInfo's destination
+  CreateGroup?: {
+    members: UserId[],
+  },
+  AddMember?: {
+    user: UserId,
+  },
+  LeaveGroup?: null,
+  GroupInfo?: {
+    members: UserId[],
+  },
+  // confirm everything up to seq arrived on this connection
+  Ack?: {
+    seq: Seq,
+  },
+  // this many queued packets were dropped while you were away
+  Missed?: {
+    count: number,
+  },
+  // reconnect with /updates/<uid>?session=<session>&seq=<last acked> to resume
+  Welcome?: {
+    session: Uuid,
+    resumed: boolean,
+  },
+  // every version of a sent message, oldest first
+  EditHistoryRequest?: {
+    uuid: Uuid,
+  },
+  EditHistory?: {
+    uuid: Uuid,
+    versions: MessageVersion[],
+  },
+  // something you sent was refused; uuid is the message it was about,
+  // request_id the one you gave the packet
+  Error?: {
+    code: ErrorCode,
+    uuid?: Uuid | null,
+    request_id?: RequestId | null,
+  },
+  // a packet you gave a request_id went through
+  Accepted?: {
+    request_id: RequestId,
+  },
+  // take back a message you sent; relayed to everyone in the conversation
+  DeleteMessage?: {
+    uuid: Uuid,
+  },
+  // how much the destination sees of your drafts (or every conversation's default, with everywhere);
+  // null goes back to the default
+  SetDraftVisibility?: {
+    visibility: DraftVisibility | null,
+    everywhere?: boolean,
+  },
+  // watch a finished message being typed again; speed is a percentage of the original pace
+  ReplayRequest?: {
+    uuid: Uuid,
+    speed?: number,
+  },
+  // one change of a replay, starting from empty content
+  Replay?: {
+    uuid: Uuid,
+    time: Timestamp,
+    op: EditOp,
+    last: boolean,
+  },
+  // you've seen this message in the conversation with the destination; its sender gets a Receipt
+  MarkRead?: {
+    uuid: Uuid,
+  },
+  // a message you sent was delivered to (or read by) whoever this is from
+  Receipt?: {
+    uuid: Uuid,
+    status: ReceiptStatus,
+    time: Timestamp,
+  },
+  // ask how far a message you sent has got; answered by Receipts
+  ReceiptsRequest?: {
+    uuid: Uuid,
+  },
+  Receipts?: {
+    uuid: Uuid,
+    receipts: ReceiptRecord[],
+  },
+  // every stored reply to a message in the conversation with the destination, answered by Replies
+  RepliesRequest?: {
+    uuid: Uuid,
+  },
+  Replies?: {
+    uuid: Uuid,
+    messages: MessageRecord[],
+  },
+  // whether this connection is being used; any other packet counts as use too
+  Heartbeat?: {
+    idle: boolean,
+  },
+  // also get Presence for these users, not just the people you talk to. Answered with their current Presence
+  SubscribePresence?: {
+    users: UserId[],
+  },
+  UnsubscribePresence?: {
+    users: UserId[],
+  },
+  // whoever this is from is now `status`; last_seen is set unless they're online
+  Presence?: {
+    status: PresenceStatus,
+    last_seen?: Timestamp | null,
+  },
+}
+
+interface MessageVersion {
+  content: string,
+  time: Timestamp,
+}
+
+type ReceiptStatus = 'Delivered' | 'Read';
+
+type PresenceStatus = 'Online' | 'Idle' | 'Offline';
+
+interface ReceiptRecord {
+  user: UserId,
+  status: ReceiptStatus,
+  time: Timestamp,
+}
+
+type ErrorCode =
+  | 'BadPacket'
+  | 'ServerOnly'
+  | 'MissingDraft'
+  | 'NotDraftOwner'
+  | 'BadEndDraft'
+  | 'MissingMessage'
+  | 'MissingConversation'
+  | 'NotAuthor'
+  | 'MessageDeleted'
+  | 'NotAMember'
+  | 'NotAGroup'
+  | 'StaleRevision'
+  | 'OutOfBounds'
+  | 'TooManyReplays'
+  | 'Internal';
+
+type DraftVisibility = 'Live' | 'Typing' | 'Hidden';
+
+interface EditOp {
+  // exactly one of these
+  Insert?: { offset: number, text: string },
+  Delete?: { offset: number, len: number },
+}
+
+interface MessageRecord {
+  uuid: Uuid,
+  sender: UserId,
+  content: string,
+  start_time: Timestamp,
+  end_time: Timestamp,
+  // when it was last edited, if it was
+  edited?: Timestamp | null,
+  // set (with empty content) if the message was deleted
+  deleted?: Timestamp | null,
+  // the message this replies to, if it's a reply
+  reply_to?: Uuid | null,
 }
 
 
@@ -99,6 +482,7 @@ interface Message {
 interface Draft {
   uuid?: Base64Uuid,
   content: string,
+  revision?: Revision,
   start_time?: Timestamp,
   end_time?: Timestamp,
 }
@@ -122,5 +506,38 @@ const uuid2str = (arr: Array<number>): Base64Uuid => btoa(String.fromCharCode(..
 const str2uuid = (str: Base64Uuid): Uuid => Array.from(atob(str).split('').map(c => c.charCodeAt(0)));
 const getNowTimestamp = (): Timestamp => Date.now() * 1000; // microseconds
 
-export type { Base64Uuid, Uuid, Timestamp, UserId, WebPacket, WebDest, Packet, Message, Draft };
-export { assertUuid, assertUserId, uuid2str, str2uuid, getNowTimestamp };
\ No newline at end of file
+// the changes that turn `before` into `after`: a delete and/or an insert between the common ends
+const diffOps = (before: string, after: string): EditOp[] => {
+  const from = Array.from(before);
+  const to = Array.from(after);
+  let start = 0;
+  while (start < from.length && start < to.length && from[start] === to[start]) {
+    start++;
+  }
+  let end = 0;
+  while (end < from.length - start && end < to.length - start
+    && from[from.length - 1 - end] === to[to.length - 1 - end]) {
+    end++;
+  }
+  const ops: EditOp[] = [];
+  if (from.length - start - end > 0) {
+    ops.push({ Delete: { offset: start, len: from.length - start - end } });
+  }
+  if (to.length - start - end > 0) {
+    ops.push({ Insert: { offset: start, text: to.slice(start, to.length - end).join('') } });
+  }
+  return ops;
+};
+
+const applyOp = (content: string, op: EditOp): string => {
+  const chars = Array.from(content);
+  if (op.Insert) {
+    chars.splice(op.Insert.offset, 0, op.Insert.text);
+  } else if (op.Delete) {
+    chars.splice(op.Delete.offset, op.Delete.len);
+  }
+  return chars.join('');
+};
+
+export type { Base64Uuid, Uuid, Timestamp, UserId, WebPacket, WebDest, Packet, MessageRecord, MessageVersion, Message, Draft, EditOp, Revision, DraftVisibility, ErrorCode, RequestId, ReceiptStatus, ReceiptRecord, PresenceStatus };
+export { assertUuid, assertUserId, uuid2str, str2uuid, getNowTimestamp, diffOps, applyOp };
\ No newline at end of file